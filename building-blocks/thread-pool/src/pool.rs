@@ -1,7 +1,8 @@
 use std::thread;
 use std::sync::mpsc;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, PoisonError};
 use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
 
 pub trait FnBox {
     fn call_box(self: Box<Self>);
@@ -36,6 +37,11 @@ impl ThreadPool {
         let job = Box::new(job);
         self.sender.send(Message::NewJob(job)).unwrap();
     }
+
+    /// 线程池中的线程数, 死掉的线程会被替换, 所以它不会变少
+    pub fn threads(&self) -> usize {
+        self.workers.len()
+    }
 }
 
 impl Drop for ThreadPool {
@@ -46,43 +52,84 @@ impl Drop for ThreadPool {
         for worker in &mut self.workers {
             println!("Shutting down worker: {}", worker.id);
 
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
+            // 等待的线程死掉时会先放入替代它的线程, 所以要一直等到槽位为空
+            while let Some(thread) = worker.take_thread() {
+                let _ = thread.join();
             }
         }
     }
 }
 
+type Receiver = Arc<Mutex<mpsc::Receiver<Message>>>;
+
+type Slot = Arc<Mutex<Option<thread::JoinHandle<()>>>>;
+
 struct Worker {
     id: u32,
-    thread: Option<thread::JoinHandle<()>>,
+    thread: Slot,
 }
 
 impl Worker {
-    fn new(id: u32, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        Worker {
-            id,
-            thread: Some(thread::spawn(move || {
-                loop {
-                    let message = receiver.lock().unwrap().recv().unwrap();
-
-                    
-                    match message {
-                        Message::NewJob(job) => {
-                            println!("thread number: {} get a job", id);
-                            job.call_box();
-                        }
-                        Message::Terminate => break,
+    fn new(id: u32, receiver: Receiver) -> Worker {
+        let thread = Arc::new(Mutex::new(None));
+        spawn_in(id, receiver, Arc::clone(&thread));
+        Worker { id, thread }
+    }
+
+    fn take_thread(&self) -> Option<thread::JoinHandle<()>> {
+        self.thread.lock().unwrap_or_else(PoisonError::into_inner).take()
+    }
+}
+
+/// starts a worker thread and puts its handle into the slot
+fn spawn_in(id: u32, receiver: Receiver, slot: Slot) {
+    let mut guard = slot.lock().unwrap_or_else(PoisonError::into_inner);
+    let sentinel = Sentinel { id, receiver, slot: Arc::clone(&slot) };
+    *guard = Some(thread::spawn(move || sentinel.run()));
+}
+
+/// 工作线程持有它, 线程因为 panic 退出时, 它会在 drop 时启动一个新的线程来替代
+struct Sentinel {
+    id: u32,
+    receiver: Receiver,
+    slot: Slot,
+}
+
+impl Sentinel {
+    fn run(&self) {
+        loop {
+            // 其他线程 panic 时可能毒化了这个锁, 队列本身不会因此损坏
+            let message = self.receiver.lock().unwrap_or_else(PoisonError::into_inner).recv();
+            let message = match message {
+                Ok(message) => message,
+                Err(_) => break,
+            };
+
+            match message {
+                Message::NewJob(job) => {
+                    println!("thread number: {} get a job", self.id);
+                    if panic::catch_unwind(AssertUnwindSafe(|| job.call_box())).is_err() {
+                        println!("thread number: {} job panicked", self.id);
                     }
                 }
-            }))
+                Message::Terminate => break,
+            }
         }
     }
 }
 
-type Job = Box<FnBox + Send + 'static>;
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            println!("thread number: {} died, starting a new one", self.id);
+            spawn_in(self.id, Arc::clone(&self.receiver), Arc::clone(&self.slot));
+        }
+    }
+}
+
+type Job = Box<dyn FnBox + Send + 'static>;
 
 enum Message {
     NewJob(Job),
     Terminate,
-}
\ No newline at end of file
+}
@@ -0,0 +1,373 @@
+use crate::client::{utf8_pairs, DEFAULT_TIMEOUT};
+use crate::protocol::{read_chunk_async, write_chunk_async, CHUNK_LEN};
+use crate::protocol::{read_frame_async, write_frame_async};
+use crate::{KvsError, Request, Response, Result, WriteBatch};
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{lookup_host, TcpStream, ToSocketAddrs};
+
+/// A tokio based client of `kvs-server`, the asynchronous `KvsClient`.
+///
+/// ```no_run
+/// use kvs::AsyncKvsClient;
+///
+/// # #[tokio::main]
+/// # async fn main() -> kvs::Result<()> {
+/// let mut client = AsyncKvsClient::connect("127.0.0.1:4000").await?;
+/// client.set("key".to_owned(), "value".to_owned()).await?;
+/// assert_eq!(client.get("key".to_owned()).await?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncKvsClient {
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+    connection: Option<Connection>,
+}
+
+struct Connection {
+    reader: BufReader<OwnedReadHalf>,
+    writer: BufWriter<OwnedWriteHalf>,
+}
+
+impl AsyncKvsClient {
+    /// connects to the server with `DEFAULT_TIMEOUT`
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<AsyncKvsClient> {
+        AsyncKvsClient::connect_timeout(addr, Some(DEFAULT_TIMEOUT)).await
+    }
+
+    /// connects to the server, `None` waits for the server as long as it takes
+    pub async fn connect_timeout(
+        addr: impl ToSocketAddrs,
+        timeout: Option<Duration>,
+    ) -> Result<AsyncKvsClient> {
+        let addr = lookup_host(addr)
+            .await?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to connect to"))?;
+        let mut client = AsyncKvsClient {
+            addr,
+            timeout,
+            connection: None,
+        };
+        client.connection().await?;
+        Ok(client)
+    }
+
+    /// gets the value of the key, `None` if the key does not exist
+    pub async fn get_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key: key.into() }).await? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `get_bytes` for values holding utf-8, `KvsError::Utf8Error` for the others
+    pub async fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self
+            .get_bytes(key)
+            .await?
+            .map(String::from_utf8)
+            .transpose()?)
+    }
+
+    /// copies the value of the key into `writer` as it comes from the server chunk by chunk,
+    /// `false` if the key does not exist
+    pub async fn get_into(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        writer: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<bool> {
+        match self
+            .request(&Request::GetStream { key: key.into() })
+            .await?
+        {
+            Response::Stream => (),
+            Response::NotFound => return Ok(false),
+            response => return Err(unexpected(response)),
+        }
+        let result = self.receive_value(writer).await;
+        if result.is_err() {
+            // the rest of the value is still on the way, the connection can not be reused
+            self.connection = None;
+        }
+        match result? {
+            Response::Ok => Ok(true),
+            Response::Error(message) => Err(KvsError::StringError(message)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the value of the key to everything read from `value`,
+    /// which is sent in chunks instead of as a whole
+    pub async fn set_reader(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        mut value: impl AsyncRead + Unpin,
+    ) -> Result<()> {
+        let result = self.send_value(key.into(), &mut value).await;
+        if result.is_err() {
+            // 值没有发完, 服务端会因为连接关闭而放弃它
+            self.connection = None;
+        }
+        match result? {
+            Response::Ok => Ok(()),
+            Response::Error(message) => Err(KvsError::StringError(message)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the value of the key
+    pub async fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::Set {
+            key: key.into(),
+            value: value.into(),
+            ttl: None,
+        };
+        match self.request(&request).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the value of the key, the key expires once `ttl` has passed
+    pub async fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let request = Request::Set {
+            key: key.into(),
+            value: value.into(),
+            ttl: Some(ttl),
+        };
+        match self.request(&request).await? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// removes the key, `KvsError::KeyNotFoundError` if it does not exist
+    pub async fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        match self.request(&Request::Rm { key: key.into() }).await? {
+            Response::Ok => Ok(()),
+            Response::NotFound => Err(KvsError::KeyNotFoundError),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the key to `new` only if its current value is `expected`,
+    /// see `KvsEngine::compare_and_swap_bytes`
+    pub async fn compare_and_swap_bytes(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let request = Request::Cas {
+            key: key.into(),
+            expected,
+            new,
+        };
+        match self.request(&request).await? {
+            Response::Ok => Ok(Ok(())),
+            Response::Conflict(current) => Ok(Err(current)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `compare_and_swap_bytes` for values holding utf-8
+    pub async fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let result = self
+            .compare_and_swap_bytes(
+                key,
+                expected.map(String::into_bytes),
+                new.map(String::into_bytes),
+            )
+            .await?;
+        match result {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(current.map(String::from_utf8).transpose()?)),
+        }
+    }
+
+    /// applies every operation of the batch or none of them,
+    /// `KvsError::KeyNotFoundError` if a removed key does not exist
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let request = Request::Batch {
+            ops: batch.into_iter().collect(),
+        };
+        match self.request(&request).await? {
+            Response::Ok => Ok(()),
+            Response::NotFound => Err(KvsError::KeyNotFoundError),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// the pairs with keys from `start` (included) to `end` (excluded),
+    /// `None` leaves that side of the range open
+    pub async fn scan_bytes(
+        &mut self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            start,
+            end,
+            prefix: None,
+            limit,
+        };
+        match self.request(&request).await? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `scan_bytes` for pairs holding utf-8
+    pub async fn scan(
+        &mut self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let start = start.map(String::into_bytes);
+        let end = end.map(String::into_bytes);
+        utf8_pairs(self.scan_bytes(start, end, limit).await?)
+    }
+
+    /// the pairs with keys starting with `prefix`
+    pub async fn scan_prefix_bytes(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            start: None,
+            end: None,
+            prefix: Some(prefix.into()),
+            limit,
+        };
+        match self.request(&request).await? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `scan_prefix_bytes` for pairs holding utf-8
+    pub async fn scan_prefix(
+        &mut self,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        utf8_pairs(self.scan_prefix_bytes(prefix, limit).await?)
+    }
+
+    async fn request(&mut self, request: &Request) -> Result<Response> {
+        let timeout = self.timeout;
+        let result = with_timeout(timeout, self.round_trip(request)).await;
+        if result.is_err() {
+            // the connection is in an unknown state, a fresh one is opened next time
+            self.connection = None;
+        }
+        match result? {
+            Response::Error(message) => Err(KvsError::StringError(message)),
+            response => Ok(response),
+        }
+    }
+
+    async fn round_trip(&mut self, request: &Request) -> Result<Response> {
+        let connection = self.connection().await?;
+        write_frame_async(&mut connection.writer, request).await?;
+        connection.writer.flush().await?;
+        read_frame_async(&mut connection.reader)
+            .await?
+            .ok_or_else(|| KvsError::StringError("the server closed the connection".to_owned()))
+    }
+
+    /// the timeout applies to every chunk rather than to the whole value
+    async fn receive_value(&mut self, writer: &mut (impl AsyncWrite + Unpin)) -> Result<Response> {
+        let timeout = self.timeout;
+        let connection = self.connection().await?;
+        loop {
+            let chunk = with_timeout(timeout, read_chunk_async(&mut connection.reader)).await?;
+            if chunk.is_empty() {
+                break;
+            }
+            writer.write_all(&chunk).await?;
+        }
+        with_timeout(timeout, read_frame_async(&mut connection.reader))
+            .await?
+            .ok_or_else(|| KvsError::StringError("the server closed the connection".to_owned()))
+    }
+
+    async fn send_value(
+        &mut self,
+        key: Vec<u8>,
+        value: &mut (impl AsyncRead + Unpin),
+    ) -> Result<Response> {
+        let timeout = self.timeout;
+        let connection = self.connection().await?;
+        let writer = &mut connection.writer;
+        write_frame_async(writer, &Request::SetStream { key }).await?;
+        let mut chunk = vec![0; CHUNK_LEN];
+        loop {
+            let len = value.read(&mut chunk).await?;
+            if len == 0 {
+                break;
+            }
+            with_timeout(timeout, write_chunk_async(writer, &chunk[..len])).await?;
+        }
+        with_timeout(timeout, async {
+            write_chunk_async(writer, &[]).await?;
+            Ok(writer.flush().await?)
+        })
+        .await?;
+        with_timeout(timeout, read_frame_async(&mut connection.reader))
+            .await?
+            .ok_or_else(|| KvsError::StringError("the server closed the connection".to_owned()))
+    }
+
+    async fn connection(&mut self) -> Result<&mut Connection> {
+        if self.connection.is_none() {
+            let stream = with_timeout(self.timeout, async {
+                Ok(TcpStream::connect(self.addr).await?)
+            })
+            .await?;
+            let (reader, writer) = stream.into_split();
+            self.connection = Some(Connection {
+                reader: BufReader::new(reader),
+                writer: BufWriter::new(writer),
+            });
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    match timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, future).await {
+            Ok(result) => result,
+            Err(_) => Err(io::Error::from(ErrorKind::TimedOut).into()),
+        },
+        None => future.await,
+    }
+}
+
+fn unexpected(response: Response) -> KvsError {
+    KvsError::StringError(format!("unexpected response: {:?}", response))
+}
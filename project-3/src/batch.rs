@@ -0,0 +1,110 @@
+use crate::KvsError;
+use serde::{Deserialize, Serialize};
+use std::str::FromStr;
+
+/// A series of sets and removes applied by `KvsEngine::write_batch` as one unit:
+/// after a crash either all of them or none of them are in the store.
+///
+/// ```rust
+/// use kvs::{KvStore, KvsEngine, WriteBatch};
+///
+/// # fn main() -> kvs::Result<()> {
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStore::open(temp_dir.path())?;
+/// store.set("from".to_owned(), "10".to_owned())?;
+///
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("to".to_owned(), "10".to_owned())
+///     .remove("from".to_owned());
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// One operation of a `WriteBatch`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// sets the value of the key
+    Set {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes_serde")]
+        value: Vec<u8>,
+    },
+    /// removes the key, the whole batch fails if it does not exist
+    Rm {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// an empty batch
+    pub fn new() -> WriteBatch {
+        WriteBatch::default()
+    }
+
+    /// adds a set of the key to the batch
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// adds a remove of the key to the batch
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut WriteBatch {
+        self.ops.push(BatchOp::Rm { key: key.into() });
+        self
+    }
+
+    /// the operations in the order they are applied
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl From<Vec<BatchOp>> for WriteBatch {
+    fn from(ops: Vec<BatchOp>) -> WriteBatch {
+        WriteBatch { ops }
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+/// `KEY=VALUE` sets the key and a bare `KEY` removes it, as typed to `kvs-client batch`
+impl FromStr for BatchOp {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<BatchOp, KvsError> {
+        match s.find('=') {
+            Some(0) => Err(KvsError::StringError(format!("missing key in '{}'", s))),
+            Some(at) => Ok(BatchOp::Set {
+                key: s[..at].into(),
+                value: s[at + 1..].into(),
+            }),
+            None => Ok(BatchOp::Rm { key: s.into() }),
+        }
+    }
+}
@@ -1,8 +1,5 @@
-use kvs::Command;
-use kvs::Result;
-use serde_json;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpStream;
+use kvs::{Command, KvsClient, KvsError, Result};
+use std::io::{self, Write};
 use std::process::exit;
 use structopt::StructOpt;
 
@@ -18,37 +15,80 @@ struct ClientOpt {
 fn main() -> Result<()> {
     let opt = ClientOpt::from_args();
 
-    let stream = TcpStream::connect(opt.addr)?;
-    let mut reader = BufReader::new(stream.try_clone()?);
-    let mut writer = BufWriter::new(stream);
+    let mut client = KvsClient::connect(opt.addr)?;
 
     match opt.cmd {
-        Command::Set { key: _, value: _ } => {
-            let cmd = serde_json::to_string(&opt.cmd)?;
-            writer.write(cmd.as_bytes())?;
-            writer.flush()?;
+        Command::Set {
+            key,
+            value,
+            ttl: Some(ttl),
+        } => client.set_with_ttl(key, value, ttl),
+        Command::Set { key, value, .. } => client.set(key, value),
+        Command::Get { key } => {
+            // 大的值边收边写, 不整个放进内存
+            match client.get_reader(key)? {
+                Some(mut value) => {
+                    let mut stdout = io::stdout();
+                    io::copy(&mut value, &mut stdout)?;
+                    stdout.write_all(b"\n")?;
+                }
+                None => println!("Key not found"),
+            }
             Ok(())
         }
-        Command::Get { key: _ } => {
-            let cmd = serde_json::to_string(&opt.cmd)?;
-            writer.write(cmd.as_bytes())?;
-            writer.flush()?;
-            let mut response = String::new();
-            reader.read_to_string(&mut response)?;
-            println!("{}", response);
+        Command::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+        } => {
+            let pairs = match prefix {
+                Some(prefix) => client.scan_prefix_bytes(prefix, limit)?,
+                None => client.scan_bytes(
+                    start.map(String::into_bytes),
+                    end.map(String::into_bytes),
+                    limit,
+                )?,
+            };
+            for (key, value) in pairs {
+                print_bytes(&[&key[..], b"\t", &value[..]].concat())?;
+            }
             Ok(())
         }
-        Command::Rm { key: _ } => {
-            let cmd = serde_json::to_string(&opt.cmd)?;
-            writer.write(cmd.as_bytes())?;
-            writer.flush()?;
-            let mut response = String::new();
-            reader.read_to_string(&mut response)?;
-            if response.len() > 0 {
-                eprintln!("{}", response);
+        Command::Rm { key } => match client.remove(key) {
+            Err(KvsError::KeyNotFoundError) => {
+                eprintln!("Key not found");
+                exit(1);
+            }
+            result => result,
+        },
+        Command::Cas { key, expected, new } => {
+            // 冲突时打印当前的值
+            let expected = expected.map(String::into_bytes);
+            let new = new.map(String::into_bytes);
+            if let Err(current) = client.compare_and_swap_bytes(key, expected, new)? {
+                match current {
+                    Some(value) => print_bytes(&value)?,
+                    None => println!("Key not found"),
+                }
                 exit(1);
             }
             Ok(())
         }
+        Command::Batch { ops } => match client.write_batch(ops.into()) {
+            Err(KvsError::KeyNotFoundError) => {
+                eprintln!("Key not found");
+                exit(1);
+            }
+            result => result,
+        },
     }
 }
+
+/// prints a line of raw bytes, the values are not necessarily utf-8
+fn print_bytes(bytes: &[u8]) -> Result<()> {
+    let mut stdout = io::stdout();
+    stdout.write_all(bytes)?;
+    stdout.write_all(b"\n")?;
+    Ok(())
+}
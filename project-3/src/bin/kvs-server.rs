@@ -1,14 +1,32 @@
 use clap::arg_enum;
+use kvs::protocol::{read_chunk_async, write_chunk, write_chunk_async, ChunkReader, CHUNK_LEN};
+use kvs::protocol::{read_frame, read_frame_async, write_frame, write_frame_async, MAX_FRAME_LEN};
+use kvs::resp::{self, Reply, Value};
+use kvs::thread_pool::{NaiveThreadPool, RayonThreadPool, SharedQueueThreadPool};
 use kvs::SledStore;
-use kvs::{Command, KvStore, KvsEngine, KvsError, Result};
-use slog::info;
+use kvs::{
+    AsyncKvsEngine, Blocking, Compression, EncryptionKey, HttpGateway, KvStoreOptions, KvsEngine,
+    KvsError, KvsFuture, LogFormat, Request, Response, Result, ThreadPool,
+};
+use slog::{error, info, warn, Logger};
 use sloggers::terminal::{Destination, TerminalLoggerBuilder};
 use sloggers::types::Severity;
 use sloggers::Build;
 use std::env::current_dir;
-use std::io::{BufReader, BufWriter, Read, Write};
-use std::net::TcpListener;
+use std::future::Future;
+use std::io::{BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::{Shutdown, TcpListener, TcpStream};
+use std::ops::Bound;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, available_parallelism};
+use std::time::Duration;
 use structopt::StructOpt;
+use tokio::io::{self, AsyncWrite, AsyncWriteExt};
+use tokio::runtime;
+use tokio::sync::mpsc::{self, Receiver};
 
 arg_enum! {
     #[derive(Debug, Clone)]
@@ -18,6 +36,39 @@ arg_enum! {
     }
 }
 
+arg_enum! {
+    #[derive(Debug, Clone)]
+    enum Mode {
+        Sync,
+        Async,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum Protocol {
+        Kvs,
+        Resp,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone, Copy)]
+    enum Format {
+        Json,
+        Binary,
+    }
+}
+
+arg_enum! {
+    #[derive(Debug, Clone)]
+    enum Pool {
+        Naive,
+        SharedQueue,
+        Rayon,
+    }
+}
+
 #[derive(Debug, StructOpt, Clone)]
 struct ServerOpt {
     #[structopt(long, possible_values = &Engine::variants(), case_insensitive = true)]
@@ -25,11 +76,83 @@ struct ServerOpt {
 
     #[structopt(long, default_value = "127.0.0.1:4000")]
     addr: String,
+
+    /// Also serve the HTTP/JSON gateway on this address
+    #[structopt(long)]
+    http_addr: Option<String>,
+
+    /// Speak the kvs protocol or the Redis protocol (resp, sync mode only)
+    #[structopt(long, possible_values = &Protocol::variants(), case_insensitive = true, default_value = "Kvs")]
+    protocol: Protocol,
+
+    /// Serve the connections on a thread pool (sync) or on a tokio runtime (async)
+    #[structopt(long, possible_values = &Mode::variants(), case_insensitive = true, default_value = "Sync")]
+    mode: Mode,
+
+    /// The thread pool serving the connections in sync mode
+    #[structopt(long, possible_values = &Pool::variants(), case_insensitive = true, default_value = "SharedQueue")]
+    pool: Pool,
+
+    /// How many threads the pool or the tokio runtime runs, the number of CPUs by default,
+    /// in sync mode every connection takes a thread and those over the limit are refused
+    #[structopt(long, parse(try_from_str = parse_threads))]
+    threads: Option<u32>,
+
+    /// Close kvs and resp connections idle for this many seconds (sync mode), 0 keeps them open
+    #[structopt(long, default_value = "60")]
+    idle_timeout: u64,
+
+    /// The format new kvs log records are written in, only binary logs stream large values
+    #[structopt(long, possible_values = &Format::variants(), case_insensitive = true, default_value = "Binary")]
+    format: Format,
+
+    /// Compact the kvs log once more than this many bytes of it are stale
+    #[structopt(long, default_value = "1048576")]
+    compaction_threshold: u64,
+
+    /// Also compact the kvs log once its stale bytes exceed this multiple of the live bytes
+    #[structopt(long, parse(try_from_str = parse_ratio))]
+    compaction_ratio: Option<f64>,
+
+    /// Also compact the kvs log every this many seconds
+    #[structopt(long)]
+    compaction_interval: Option<u64>,
+
+    /// Never compact the kvs log automatically
+    #[structopt(long)]
+    manual_compaction: bool,
+
+    /// Deflate the values written to the kvs log
+    #[structopt(long)]
+    compress: bool,
+
+    /// Store values shorter than this many bytes raw even with --compress
+    #[structopt(long, default_value = "256")]
+    compression_threshold: usize,
+
+    /// Encrypt the kvs log with the key in this file, 32 bytes or 64 hex digits
+    #[structopt(long, parse(from_os_str))]
+    key_file: Option<PathBuf>,
+
+    /// Also read the parts of the kvs log encrypted with the key in this file,
+    /// they are re-encrypted with --key-file by the next compaction
+    #[structopt(long, parse(from_os_str), number_of_values = 1)]
+    old_key_file: Vec<PathBuf>,
+
+    /// Also read the parts of the kvs log in plain text while --key-file is given,
+    /// they are encrypted by the next compaction
+    #[structopt(long)]
+    migrate_plaintext: bool,
 }
 
 fn main() -> Result<()> {
     let opt = ServerOpt::from_args();
 
+    let mut builder = TerminalLoggerBuilder::new();
+    builder.level(Severity::Debug);
+    builder.destination(Destination::Stderr);
+    let logger = builder.build().unwrap();
+
     let engine = opt.clone().engine.unwrap_or(Engine::Kvs);
 
     let paths = std::fs::read_dir(current_dir()?).unwrap();
@@ -46,7 +169,7 @@ fn main() -> Result<()> {
             .unwrap()
             .to_string_lossy()
             .into_owned();
-        if file_name.starts_with("kvs") {
+        if file_name.starts_with("kvs") || file_name.ends_with(".log") {
             kvs_exist = Some(file_name);
             break;
         } else if file_name.starts_with("sled") {
@@ -58,82 +181,537 @@ fn main() -> Result<()> {
     match engine {
         Engine::Kvs => {
             if sled_exist.is_some() {
-                return Err(KvsError::WrongEngineError);
+                Err(KvsError::WrongEngineError)
             } else {
-                run(KvStore::open(current_dir()?)?, opt)
+                let mut options = KvStoreOptions::new();
+                let format = match opt.format {
+                    Format::Json => LogFormat::Json,
+                    Format::Binary => LogFormat::Binary,
+                };
+                options
+                    .format(format)
+                    .compaction_threshold(opt.compaction_threshold)
+                    .manual_compaction(opt.manual_compaction)
+                    .compression_threshold(opt.compression_threshold);
+                if opt.compress {
+                    options.compression(Compression::Deflate);
+                }
+                if let Some(path) = &opt.key_file {
+                    options.encryption_key(EncryptionKey::from_file(path)?);
+                }
+                for path in &opt.old_key_file {
+                    options.decryption_key(EncryptionKey::from_file(path)?);
+                }
+                options.migrate_plaintext(opt.migrate_plaintext);
+                if let Some(ratio) = opt.compaction_ratio {
+                    options.stale_ratio(ratio);
+                }
+                if let Some(secs) = opt.compaction_interval {
+                    options.compaction_interval(Duration::from_secs(secs));
+                }
+                let store = options.open(current_dir()?)?;
+                if store.discarded_bytes() > 0 {
+                    warn!(
+                        logger,
+                        "discarded {} bytes of torn or corrupted log records",
+                        store.discarded_bytes()
+                    );
+                }
+                start(store, opt, logger)
             }
         }
         Engine::Sled => {
             if kvs_exist.is_some() {
-                return Err(KvsError::WrongEngineError);
+                Err(KvsError::WrongEngineError)
+            } else if opt.key_file.is_some() {
+                Err(KvsError::StringError(
+                    "encryption is only supported by the kvs engine".to_owned(),
+                ))
             } else {
-                run(SledStore::open(current_dir()?)?, opt)
+                start(SledStore::open(current_dir()?)?, opt, logger)
             }
         }
     }
 }
 
-fn run(mut store: impl KvsEngine, opt: ServerOpt) -> Result<()> {
-    let mut builder = TerminalLoggerBuilder::new();
-    builder.level(Severity::Debug);
-    builder.destination(Destination::Stderr);
-    let logger = builder.build().unwrap();
-
-    let listener = TcpListener::bind(&opt.addr)?;
-
-    let engine = if opt.engine.is_none() {
-        Engine::Kvs
-    } else {
-        opt.engine.unwrap()
-    };
-
+fn start(store: impl KvsEngine, opt: ServerOpt, logger: Logger) -> Result<()> {
     info!(logger, "initiate the database server");
     info!(
         logger,
         "version: {} engine: {} address: {}",
         env!("CARGO_PKG_VERSION"),
-        engine,
+        opt.engine.clone().unwrap_or(Engine::Kvs),
         opt.addr
     );
+    if let Some(http_addr) = &opt.http_addr {
+        let gateway = HttpGateway::bind(store.clone(), http_addr)?;
+        let pool = SharedQueueThreadPool::new(threads(&opt))?;
+        info!(logger, "http gateway: {}", http_addr);
+        thread::spawn(move || gateway.run(pool));
+    }
+    match (opt.mode.clone(), opt.protocol) {
+        (Mode::Sync, _) => run_with_pool(store, opt, logger),
+        (Mode::Async, Protocol::Kvs) => run_async(Blocking::new(store), opt, logger),
+        (Mode::Async, Protocol::Resp) => Err(KvsError::StringError(
+            "the resp protocol is only served in sync mode".to_owned(),
+        )),
+    }
+}
 
+// 没有线程的线程池永远不会处理连接
+fn parse_threads(s: &str) -> Result<u32> {
+    s.parse::<u32>()
+        .ok()
+        .filter(|&threads| threads >= 1)
+        .ok_or_else(|| KvsError::StringError(format!("invalid number of threads '{}'", s)))
+}
+
+fn parse_ratio(s: &str) -> Result<f64> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|&ratio| ratio.is_finite() && ratio > 0.0)
+        .ok_or_else(|| KvsError::StringError(format!("invalid compaction ratio '{}'", s)))
+}
+
+fn threads(opt: &ServerOpt) -> u32 {
+    match opt.threads {
+        Some(threads) => threads,
+        None => available_parallelism().map_or(1, |n| n.get() as u32),
+    }
+}
+
+fn run_with_pool(store: impl KvsEngine, opt: ServerOpt, logger: Logger) -> Result<()> {
+    let threads = threads(&opt);
+    match opt.pool {
+        Pool::Naive => run(store, NaiveThreadPool::new(threads)?, opt, logger),
+        Pool::SharedQueue => run(store, SharedQueueThreadPool::new(threads)?, opt, logger),
+        Pool::Rayon => run(store, RayonThreadPool::new(threads)?, opt, logger),
+    }
+}
+
+fn run(store: impl KvsEngine, pool: impl ThreadPool, opt: ServerOpt, logger: Logger) -> Result<()> {
+    let listener = TcpListener::bind(&opt.addr)?;
+    info!(
+        logger,
+        "thread pool: {} protocol: {}", opt.pool, opt.protocol
+    );
+
+    let idle_timeout = match opt.idle_timeout {
+        0 => None,
+        secs => Some(Duration::from_secs(secs)),
+    };
+    // 每个连接占用线程池的一个线程，多出来的连接直接拒绝，而不是排队等到空闲的连接超时
+    let max_connections = threads(&opt) as usize;
+    let connections = Arc::new(AtomicUsize::new(0));
     for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                // let remote_addr = stream.peer_addr()?;
-                // info!(logger, "connection from remote address: {}", remote_addr);
-                let mut reader = BufReader::new(stream.try_clone()?);
-                let mut writer = BufWriter::new(stream);
-                let mut buf = [0; 512];
-                let byte_num = reader.read(&mut buf)?;
-                // println!("{}", byte_num);
-                let request =
-                    String::from_utf8((&buf[0..byte_num]).to_vec()).expect("Found invalid utf-8");
-                // println!("{}", request);
-                let cmd = serde_json::from_str(&request)?;
-                match cmd {
-                    Command::Set { key, value } => {
-                        store.set(key, value)?;
+        // 空闲的连接不能一直占着线程池的线程
+        match stream.and_then(|stream| stream.set_read_timeout(idle_timeout).map(|()| stream)) {
+            Ok(stream) if connections.load(Ordering::SeqCst) >= max_connections => {
+                warn!(
+                    logger,
+                    "refused a connection over the limit of {}", max_connections
+                );
+                let logger = logger.clone();
+                let protocol = opt.protocol;
+                thread::spawn(move || {
+                    if let Err(e) = refuse(stream, protocol, max_connections) {
+                        error!(logger, "failed to refuse the connection: {}", e);
                     }
-                    Command::Get { key } => {
-                        let response = match store.get(key.to_owned())? {
-                            None => "Key not found".to_owned(),
-                            Some(value) => value,
-                        };
-                        writer.write(response.as_bytes())?;
-                        writer.flush()?;
+                });
+            }
+            Ok(stream) => {
+                let store = store.clone();
+                let logger = logger.clone();
+                let protocol = opt.protocol;
+                let connection = Connection::open(&connections);
+                pool.spawn(move || {
+                    let _connection = connection;
+                    let result = match protocol {
+                        Protocol::Kvs => serve(store, stream),
+                        Protocol::Resp => serve_resp(store, stream),
+                    };
+                    if let Err(e) = result {
+                        error!(logger, "failed to serve the connection: {}", e);
                     }
-                    Command::Rm { key } => match store.remove(key.to_owned()) {
-                        Ok(()) => (),
-                        Err(KvsError::KeyNotFoundError) => {
-                            writer.write("Key not found".as_bytes())?;
-                            writer.flush()?;
+                });
+            }
+            Err(e) => error!(logger, "connection failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+/// how long a refused connection is read from before it is closed
+const REFUSAL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// counts a connection served by the pool until it is dropped,
+/// also when serving it panics
+struct Connection(Arc<AtomicUsize>);
+
+impl Connection {
+    fn open(connections: &Arc<AtomicUsize>) -> Connection {
+        connections.fetch_add(1, Ordering::SeqCst);
+        Connection(Arc::clone(connections))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// tells the client every thread of the pool is serving a connection, and closes it
+fn refuse(mut stream: TcpStream, protocol: Protocol, max_connections: usize) -> Result<()> {
+    let refusal = match protocol {
+        Protocol::Kvs => write_frame(
+            &mut stream,
+            &Response::Error(format!(
+                "the server is serving its limit of {} connections",
+                max_connections
+            )),
+        ),
+        // redis 在连接数满的时候也是这样回复
+        Protocol::Resp => {
+            Value::Error("ERR max number of clients reached".to_owned()).write_to(&mut stream)
+        }
+    };
+    refusal?;
+    // 读掉客户端已经发来的请求再关闭，否则连接被重置，客户端可能收不到回复
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_read_timeout(Some(REFUSAL_TIMEOUT))?;
+    let _ = std::io::copy(&mut stream.take(MAX_FRAME_LEN as u64), &mut std::io::sink());
+    Ok(())
+}
+
+fn serve(store: impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let request = match read_frame(&mut reader) {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(ref e) if timed_out(e) => break,
+            // 整个帧已经读完，回复错误后连接还能继续使用
+            Err(KvsError::SerdeError(e)) => {
+                write_frame(&mut writer, &malformed(e))?;
+                writer.flush()?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        match request {
+            Request::SetStream { key } => {
+                let mut chunks = ChunkReader::new(&mut reader);
+                let result = store.set_reader(key, &mut chunks);
+                // 失败时跳过剩下的块, 连接才能继续使用
+                chunks.finish()?;
+                write_frame(&mut writer, &set_response(result))?;
+            }
+            Request::GetStream { key } => send_value(&store, key, &mut writer)?,
+            request => write_frame(&mut writer, &execute(&store, request))?,
+        }
+        // 还有流水线中的请求时先不发送, 一起刷新
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn serve_resp(store: impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match resp::read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(ref e) if timed_out(e) => break,
+            // redis 也是回复错误后关闭连接
+            Err(KvsError::StringError(message)) => {
+                Value::Error(format!("ERR {}", message)).write_to(&mut writer)?;
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        match resp::execute(&store, args) {
+            Reply::Value(value) => value.write_to(&mut writer)?,
+            Reply::Close(value) => {
+                value.write_to(&mut writer)?;
+                break;
+            }
+        }
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// the answer to a frame whose body is not a request
+fn malformed(e: serde_json::Error) -> Response {
+    Response::Error(format!("malformed request: {}", e))
+}
+
+/// whether reading a request failed because the connection was idle past `--idle-timeout`
+fn timed_out(e: &KvsError) -> bool {
+    match e {
+        KvsError::IoError(e) => matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut),
+        _ => false,
+    }
+}
+
+fn execute(store: &impl KvsEngine, request: Request) -> Response {
+    match request {
+        Request::Set { key, value, ttl } => set_response(match ttl {
+            Some(ttl) => store.set_with_ttl(key, value, ttl),
+            None => store.set(key, value),
+        }),
+        Request::Get { key } => get_response(store.get_bytes(key)),
+        Request::Rm { key } => remove_response(store.remove(key)),
+        Request::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+        } => {
+            let limit = limit.unwrap_or(usize::MAX);
+            let pairs = match prefix {
+                Some(prefix) => store.scan_prefix_bytes(prefix),
+                None => store.scan_bytes(scan_range(start, end)),
+            };
+            scan_response(pairs.and_then(|pairs| pairs.take(limit).collect()))
+        }
+        Request::Cas { key, expected, new } => {
+            cas_response(store.compare_and_swap_bytes(key, expected, new))
+        }
+        // a batch fails like a remove when one of its keys is missing
+        Request::Batch { ops } => remove_response(store.write_batch(ops.into())),
+        Request::GetStream { .. } | Request::SetStream { .. } => {
+            unreachable!("streams are served by the connection")
+        }
+    }
+}
+
+/// answers a `Request::GetStream` with the value in chunks
+fn send_value(store: &impl KvsEngine, key: Vec<u8>, writer: &mut impl Write) -> Result<()> {
+    let mut value = match store.get_reader(key) {
+        Ok(Some(value)) => value,
+        Ok(None) => return write_frame(writer, &Response::NotFound),
+        Err(e) => return write_frame(writer, &Response::Error(e.to_string())),
+    };
+    write_frame(writer, &Response::Stream)?;
+    let mut chunk = vec![0; CHUNK_LEN];
+    // a failure past the first chunk can only be told after the last one
+    let result = loop {
+        match value.read(&mut chunk) {
+            Ok(0) => break Response::Ok,
+            Ok(len) => write_chunk(writer, &chunk[..len])?,
+            Err(e) if e.kind() == ErrorKind::Interrupted => (),
+            Err(e) => break Response::Error(e.to_string()),
+        }
+    };
+    write_chunk(writer, &[])?;
+    write_frame(writer, &result)
+}
+
+fn run_async(store: impl AsyncKvsEngine, opt: ServerOpt, logger: Logger) -> Result<()> {
+    let runtime = runtime::Builder::new_multi_thread()
+        .worker_threads(threads(&opt) as usize)
+        .enable_all()
+        .build()?;
+    runtime.block_on(async move {
+        let listener = tokio::net::TcpListener::bind(&opt.addr).await?;
+        info!(logger, "runtime: tokio");
+
+        loop {
+            match listener.accept().await {
+                Ok((stream, _)) => {
+                    let store = store.clone();
+                    let logger = logger.clone();
+                    tokio::spawn(async move {
+                        if let Err(e) = serve_async(store, stream).await {
+                            error!(logger, "failed to serve the connection: {}", e);
                         }
-                        Err(e) => return Err(e),
-                    },
+                    });
+                }
+                Err(e) => error!(logger, "connection failed: {}", e),
+            }
+        }
+    })
+}
+
+/// how many chunks of a value being set wait for the engine
+const STREAM_CHUNKS: usize = 4;
+
+async fn serve_async(store: impl AsyncKvsEngine, stream: tokio::net::TcpStream) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = io::BufReader::new(reader);
+    let mut writer = io::BufWriter::new(writer);
+    loop {
+        let request = match read_frame_async(&mut reader).await {
+            Ok(Some(request)) => request,
+            Ok(None) => break,
+            Err(KvsError::SerdeError(e)) => {
+                write_frame_async(&mut writer, &malformed(e)).await?;
+                writer.flush().await?;
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        match request {
+            Request::SetStream { key } => {
+                let (sender, chunks) = mpsc::channel(STREAM_CHUNKS);
+                let set = tokio::spawn(store.set_chunks(key, chunks));
+                loop {
+                    let chunk = read_chunk_async(&mut reader).await?;
+                    let last = chunk.is_empty();
+                    // the engine stops taking chunks once it failed, the rest are skipped
+                    let _ = sender.send(chunk).await;
+                    if last {
+                        break;
+                    }
                 }
+                drop(sender);
+                let result = set.await.unwrap_or_else(|e| {
+                    Err(KvsError::StringError(format!("engine task failed: {}", e)))
+                });
+                write_frame_async(&mut writer, &set_response(result)).await?;
+            }
+            Request::GetStream { key } => {
+                let chunks = store.get_chunks(key);
+                send_value_async(chunks, &mut writer).await?
+            }
+            request => {
+                let response = execute_async(&store, request);
+                write_frame_async(&mut writer, &response.await).await?;
             }
-            Err(_) => {}
+        }
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
         }
     }
+    writer.flush().await?;
     Ok(())
 }
+
+// the future does not borrow the store, so the engine does not have to be `Sync`
+fn execute_async(
+    store: &impl AsyncKvsEngine,
+    request: Request,
+) -> Pin<Box<dyn Future<Output = Response> + Send>> {
+    match request {
+        Request::Set { key, value, ttl } => {
+            let set = match ttl {
+                Some(ttl) => store.set_with_ttl(key, value, ttl),
+                None => store.set(key, value),
+            };
+            Box::pin(async move { set_response(set.await) })
+        }
+        Request::Get { key } => {
+            let get = store.get_bytes(key);
+            Box::pin(async move { get_response(get.await) })
+        }
+        Request::Rm { key } => {
+            let remove = store.remove(key);
+            Box::pin(async move { remove_response(remove.await) })
+        }
+        Request::Scan {
+            start,
+            end,
+            prefix,
+            limit,
+        } => {
+            let limit = limit.unwrap_or(usize::MAX);
+            let scan = match prefix {
+                Some(prefix) => store.scan_prefix_bytes(prefix, limit),
+                None => store.scan_bytes(scan_range(start, end), limit),
+            };
+            Box::pin(async move { scan_response(scan.await) })
+        }
+        Request::Cas { key, expected, new } => {
+            let cas = store.compare_and_swap_bytes(key, expected, new);
+            Box::pin(async move { cas_response(cas.await) })
+        }
+        Request::Batch { ops } => {
+            let batch = store.write_batch(ops.into());
+            Box::pin(async move { remove_response(batch.await) })
+        }
+        Request::GetStream { .. } | Request::SetStream { .. } => {
+            unreachable!("streams are served by the connection")
+        }
+    }
+}
+
+/// `send_value` for the tokio server, the future does not borrow the store either
+async fn send_value_async(
+    chunks: KvsFuture<Option<Receiver<Result<Vec<u8>>>>>,
+    writer: &mut (impl AsyncWrite + Unpin),
+) -> Result<()> {
+    let mut chunks = match chunks.await {
+        Ok(Some(chunks)) => chunks,
+        Ok(None) => return write_frame_async(writer, &Response::NotFound).await,
+        Err(e) => return write_frame_async(writer, &Response::Error(e.to_string())).await,
+    };
+    write_frame_async(writer, &Response::Stream).await?;
+    let mut result = Response::Ok;
+    while let Some(chunk) = chunks.recv().await {
+        match chunk {
+            Ok(chunk) => write_chunk_async(writer, &chunk).await?,
+            Err(e) => {
+                result = Response::Error(e.to_string());
+                break;
+            }
+        }
+    }
+    write_chunk_async(writer, &[]).await?;
+    write_frame_async(writer, &result).await
+}
+
+/// `start` is included and `end` excluded, a missing one leaves the range open
+fn scan_range(start: Option<Vec<u8>>, end: Option<Vec<u8>>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    (
+        start.map_or(Bound::Unbounded, Bound::Included),
+        end.map_or(Bound::Unbounded, Bound::Excluded),
+    )
+}
+
+fn scan_response(result: Result<Vec<(Vec<u8>, Vec<u8>)>>) -> Response {
+    match result {
+        Ok(pairs) => Response::Pairs(pairs),
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+fn set_response(result: Result<()>) -> Response {
+    match result {
+        Ok(()) => Response::Ok,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+fn get_response(result: Result<Option<Vec<u8>>>) -> Response {
+    match result {
+        Ok(Some(value)) => Response::Value(value),
+        Ok(None) => Response::NotFound,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+fn cas_response(result: Result<std::result::Result<(), Option<Vec<u8>>>>) -> Response {
+    match result {
+        Ok(Ok(())) => Response::Ok,
+        Ok(Err(current)) => Response::Conflict(current),
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
+
+fn remove_response(result: Result<()>) -> Response {
+    match result {
+        Ok(()) => Response::Ok,
+        Err(KvsError::KeyNotFoundError) => Response::NotFound,
+        Err(e) => Response::Error(e.to_string()),
+    }
+}
@@ -0,0 +1,370 @@
+use crate::protocol::{read_chunk, read_frame, write_chunk, write_frame, CHUNK_LEN};
+use crate::{KvsError, Request, Response, Result, WriteBatch};
+use std::io::{self, BufReader, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// how long `KvsClient::connect` waits for connecting, reading and writing
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A client of `kvs-server`.
+///
+/// All requests go through one connection, which is opened again by the next request
+/// once it failed.
+///
+/// ```no_run
+/// use kvs::KvsClient;
+///
+/// # fn main() -> kvs::Result<()> {
+/// let mut client = KvsClient::connect("127.0.0.1:4000")?;
+/// client.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(client.get("key".to_owned())?, Some("value".to_owned()));
+/// client.remove("key".to_owned())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvsClient {
+    addr: SocketAddr,
+    timeout: Option<Duration>,
+    connection: Option<Connection>,
+}
+
+struct Connection {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// connects to the server with `DEFAULT_TIMEOUT`
+    pub fn connect(addr: impl ToSocketAddrs) -> Result<KvsClient> {
+        KvsClient::connect_timeout(addr, Some(DEFAULT_TIMEOUT))
+    }
+
+    /// connects to the server, `None` waits for the server as long as it takes
+    pub fn connect_timeout(
+        addr: impl ToSocketAddrs,
+        timeout: Option<Duration>,
+    ) -> Result<KvsClient> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "no address to connect to"))?;
+        let mut client = KvsClient {
+            addr,
+            timeout,
+            connection: None,
+        };
+        client.connection()?;
+        Ok(client)
+    }
+
+    /// gets the value of the key, `None` if the key does not exist
+    pub fn get_bytes(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get { key: key.into() })? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `get_bytes` for values holding utf-8, `KvsError::Utf8Error` for the others
+    pub fn get(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// the value of the key as a reader pulling it from the server chunk by chunk,
+    /// `None` if the key does not exist
+    ///
+    /// The connection is busy until the value is read to its end,
+    /// dropping the reader before that closes the connection.
+    pub fn get_reader(&mut self, key: impl Into<Vec<u8>>) -> Result<Option<ValueStream<'_>>> {
+        match self.request(&Request::GetStream { key: key.into() })? {
+            Response::Stream => Ok(Some(ValueStream {
+                connection: &mut self.connection,
+                chunk: Cursor::new(Vec::new()),
+                done: false,
+            })),
+            Response::NotFound => Ok(None),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the value of the key to everything read from `value`,
+    /// which is sent in chunks instead of as a whole
+    pub fn set_reader(&mut self, key: impl Into<Vec<u8>>, mut value: impl Read) -> Result<()> {
+        let result = self.send_value(key.into(), &mut value);
+        if result.is_err() {
+            // 值没有发完, 服务端会因为连接关闭而放弃它
+            self.connection = None;
+        }
+        match result? {
+            Response::Ok => Ok(()),
+            Response::Error(message) => Err(KvsError::StringError(message)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the value of the key
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let request = Request::Set {
+            key: key.into(),
+            value: value.into(),
+            ttl: None,
+        };
+        match self.request(&request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the value of the key, the key expires once `ttl` has passed
+    pub fn set_with_ttl(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let request = Request::Set {
+            key: key.into(),
+            value: value.into(),
+            ttl: Some(ttl),
+        };
+        match self.request(&request)? {
+            Response::Ok => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// removes the key, `KvsError::KeyNotFoundError` if it does not exist
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> Result<()> {
+        match self.request(&Request::Rm { key: key.into() })? {
+            Response::Ok => Ok(()),
+            Response::NotFound => Err(KvsError::KeyNotFoundError),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// sets the key to `new` only if its current value is `expected`,
+    /// see `KvsEngine::compare_and_swap_bytes`
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let request = Request::Cas {
+            key: key.into(),
+            expected,
+            new,
+        };
+        match self.request(&request)? {
+            Response::Ok => Ok(Ok(())),
+            Response::Conflict(current) => Ok(Err(current)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `compare_and_swap_bytes` for values holding utf-8
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let result = self.compare_and_swap_bytes(
+            key,
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        match result {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(current.map(String::from_utf8).transpose()?)),
+        }
+    }
+
+    /// applies every operation of the batch or none of them,
+    /// `KvsError::KeyNotFoundError` if a removed key does not exist
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let request = Request::Batch {
+            ops: batch.into_iter().collect(),
+        };
+        match self.request(&request)? {
+            Response::Ok => Ok(()),
+            Response::NotFound => Err(KvsError::KeyNotFoundError),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// the pairs with keys from `start` (included) to `end` (excluded),
+    /// `None` leaves that side of the range open
+    pub fn scan_bytes(
+        &mut self,
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            start,
+            end,
+            prefix: None,
+            limit,
+        };
+        match self.request(&request)? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `scan_bytes` for pairs holding utf-8
+    pub fn scan(
+        &mut self,
+        start: Option<String>,
+        end: Option<String>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        let start = start.map(String::into_bytes);
+        let end = end.map(String::into_bytes);
+        utf8_pairs(self.scan_bytes(start, end, limit)?)
+    }
+
+    /// the pairs with keys starting with `prefix`
+    pub fn scan_prefix_bytes(
+        &mut self,
+        prefix: impl Into<Vec<u8>>,
+        limit: Option<usize>,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            start: None,
+            end: None,
+            prefix: Some(prefix.into()),
+            limit,
+        };
+        match self.request(&request)? {
+            Response::Pairs(pairs) => Ok(pairs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// `scan_prefix_bytes` for pairs holding utf-8
+    pub fn scan_prefix(
+        &mut self,
+        prefix: String,
+        limit: Option<usize>,
+    ) -> Result<Vec<(String, String)>> {
+        utf8_pairs(self.scan_prefix_bytes(prefix, limit)?)
+    }
+
+    fn request(&mut self, request: &Request) -> Result<Response> {
+        let result = self.round_trip(request);
+        if result.is_err() {
+            // the connection is in an unknown state, a fresh one is opened next time
+            self.connection = None;
+        }
+        match result? {
+            Response::Error(message) => Err(KvsError::StringError(message)),
+            response => Ok(response),
+        }
+    }
+
+    fn round_trip(&mut self, request: &Request) -> Result<Response> {
+        let connection = self.connection()?;
+        write_frame(&mut connection.writer, request)?;
+        connection.writer.flush()?;
+        read_frame(&mut connection.reader)?
+            .ok_or_else(|| KvsError::StringError("the server closed the connection".to_owned()))
+    }
+
+    fn send_value(&mut self, key: Vec<u8>, value: &mut impl Read) -> Result<Response> {
+        let connection = self.connection()?;
+        write_frame(&mut connection.writer, &Request::SetStream { key })?;
+        let mut chunk = vec![0; CHUNK_LEN];
+        loop {
+            let len = match value.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            write_chunk(&mut connection.writer, &chunk[..len])?;
+        }
+        write_chunk(&mut connection.writer, &[])?;
+        connection.writer.flush()?;
+        read_frame(&mut connection.reader)?
+            .ok_or_else(|| KvsError::StringError("the server closed the connection".to_owned()))
+    }
+
+    fn connection(&mut self) -> Result<&mut Connection> {
+        if self.connection.is_none() {
+            let stream = match self.timeout {
+                Some(timeout) => TcpStream::connect_timeout(&self.addr, timeout)?,
+                None => TcpStream::connect(self.addr)?,
+            };
+            stream.set_read_timeout(self.timeout)?;
+            stream.set_write_timeout(self.timeout)?;
+            self.connection = Some(Connection {
+                reader: BufReader::new(stream.try_clone()?),
+                writer: BufWriter::new(stream),
+            });
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+}
+
+/// A value streamed from the server by `KvsClient::get_reader`
+pub struct ValueStream<'a> {
+    connection: &'a mut Option<Connection>,
+    chunk: Cursor<Vec<u8>>,
+    /// whether the last chunk and the response after it were read
+    done: bool,
+}
+
+impl Read for ValueStream<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = self.chunk.read(buf)?;
+            if len > 0 || buf.is_empty() || self.done {
+                return Ok(len);
+            }
+            let reader = match self.connection {
+                Some(connection) => &mut connection.reader,
+                None => return Err(ErrorKind::NotConnected.into()),
+            };
+            let chunk = read_chunk(reader)?;
+            if chunk.is_empty() {
+                // the server tells whether the whole value could be read after the last chunk
+                let response = match read_frame(reader)? {
+                    Some(response) => response,
+                    None => return Err(ErrorKind::UnexpectedEof.into()),
+                };
+                self.done = true;
+                return match response {
+                    Response::Ok => Ok(0),
+                    Response::Error(message) => Err(KvsError::StringError(message).into()),
+                    response => Err(unexpected(response).into()),
+                };
+            }
+            self.chunk = Cursor::new(chunk);
+        }
+    }
+}
+
+impl Drop for ValueStream<'_> {
+    fn drop(&mut self) {
+        if !self.done {
+            // the rest of the value is still on the way, the connection can not be reused
+            *self.connection = None;
+        }
+    }
+}
+
+/// the pairs of a scan as `String`s, `KvsError::Utf8Error` if one is not utf-8
+pub(crate) fn utf8_pairs(pairs: Vec<(Vec<u8>, Vec<u8>)>) -> Result<Vec<(String, String)>> {
+    pairs
+        .into_iter()
+        .map(|(key, value)| Ok((String::from_utf8(key)?, String::from_utf8(value)?)))
+        .collect()
+}
+
+fn unexpected(response: Response) -> KvsError {
+    KvsError::StringError(format!("unexpected response: {:?}", response))
+}
@@ -0,0 +1,78 @@
+//! compression of the values stored in the `KvStore` log
+//!
+//! Every set record says how its value is compressed, so records written with and
+//! without compression can be mixed in one log and are replayed alike.
+//! A compressed value starts with its decompressed length as a little endian u32.
+
+use crate::log_format::u32_at;
+use crate::{KvsError, Result};
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+
+/// the deflate level, the fast end trades some ratio for write throughput
+const DEFLATE_LEVEL: u8 = 1;
+
+/// How the values of a `KvStore` are compressed in its log, see `KvStoreOptions::compression`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+pub enum Compression {
+    /// values are stored as they are
+    #[default]
+    None,
+    /// values are compressed with raw deflate
+    Deflate,
+}
+
+impl Compression {
+    pub(crate) fn is_none(&self) -> bool {
+        *self == Compression::None
+    }
+
+    /// the compressed value, `None` if it is shorter than `threshold`
+    /// or does not get any smaller
+    pub(crate) fn compress(self, value: &[u8], threshold: usize) -> Option<Vec<u8>> {
+        if value.len() < threshold {
+            return None;
+        }
+        let len = u32::try_from(value.len()).ok()?;
+        let mut compressed = len.to_le_bytes().to_vec();
+        match self {
+            Compression::None => return None,
+            Compression::Deflate => {
+                compressed.extend(miniz_oxide::deflate::compress_to_vec(value, DEFLATE_LEVEL))
+            }
+        }
+        if compressed.len() < value.len() {
+            Some(compressed)
+        } else {
+            None
+        }
+    }
+
+    /// the length the value declares it has once decompressed,
+    /// `None` if a compressed value is too short to declare it
+    pub(crate) fn decompressed_len(self, value: &[u8]) -> Option<u64> {
+        match self {
+            Compression::None => Some(value.len() as u64),
+            Compression::Deflate if value.len() >= 4 => Some(u32_at(value, 0) as u64),
+            Compression::Deflate => None,
+        }
+    }
+
+    /// the decompressed value, inflating no further than its declared length
+    pub(crate) fn decompress(self, value: &[u8]) -> Result<Vec<u8>> {
+        let len = self
+            .decompressed_len(value)
+            .ok_or(KvsError::CorruptedLogError)? as usize;
+        let decompressed = match self {
+            Compression::None => value.to_vec(),
+            Compression::Deflate => {
+                miniz_oxide::inflate::decompress_to_vec_with_limit(&value[4..], len)
+                    .map_err(|_| KvsError::CorruptedLogError)?
+            }
+        };
+        if decompressed.len() != len {
+            return Err(KvsError::CorruptedLogError);
+        }
+        Ok(decompressed)
+    }
+}
@@ -0,0 +1,225 @@
+//! authenticated encryption of the `KvStore` data files
+//!
+//! Records are sealed with ChaCha20-Poly1305 as specified by RFC 8439.
+//! Every encrypted file carries a random salt in its header, its records are sealed
+//! with a key derived from the master key and that salt, and the offset of a record
+//! in the file is its nonce, so no nonce is ever used twice with the same key.
+
+use crate::log_format::{u32_at, u64_at};
+use crate::{KvsError, Result};
+use chacha20poly1305::aead::{AeadInPlace, KeyInit};
+use chacha20poly1305::{ChaCha20Poly1305, Nonce, Tag};
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, ErrorKind, Read};
+use std::path::Path;
+
+/// length of the Poly1305 tag appended to every sealed record
+const TAG_LEN: usize = 16;
+
+/// the shortest sealed record, the length and the tag of an empty one,
+/// fewer bytes left at the end of a file can only be a record torn by a crash
+pub(crate) const MIN_SEALED_LEN: u64 = 4 + TAG_LEN as u64;
+
+/// key id(8) + salt(8)
+pub(crate) const CIPHER_HEADER_LEN: usize = 16;
+
+/// A 256-bit master key encrypting the data files of a `KvStore`,
+/// see `KvStoreOptions::encryption_key`.
+#[derive(Clone)]
+pub struct EncryptionKey {
+    key: [u8; 32],
+}
+
+impl EncryptionKey {
+    /// the key made of the given bytes, which should come from a secure random source
+    pub fn new(key: [u8; 32]) -> EncryptionKey {
+        EncryptionKey { key }
+    }
+
+    /// reads a key file holding either the 32 bytes of the key
+    /// or the 64 hex digits of it, optionally followed by a newline
+    pub fn from_file(path: impl AsRef<Path>) -> Result<EncryptionKey> {
+        let content = fs::read(path)?;
+        let mut key = [0; 32];
+        if content.len() == key.len() {
+            key.copy_from_slice(&content);
+            return Ok(EncryptionKey::new(key));
+        }
+        let hex = content.trim_ascii();
+        if hex.len() != 2 * key.len() {
+            return Err(KvsError::StringError(
+                "a key file holds 32 bytes or 64 hex digits".to_owned(),
+            ));
+        }
+        for (byte, digits) in key.iter_mut().zip(hex.chunks(2)) {
+            *byte = std::str::from_utf8(digits)
+                .ok()
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| KvsError::StringError("invalid hex digit in key file".to_owned()))?;
+        }
+        Ok(EncryptionKey::new(key))
+    }
+
+    /// identifies the key in the headers of the files it encrypts,
+    /// without giving away anything about the key itself
+    pub(crate) fn id(&self) -> u64 {
+        u64_at(&self.derive(*b"kid\0", 0), 0)
+    }
+
+    /// a key of its own for every salt, the ChaCha20 key stream is the PRF
+    fn derive(&self, label: [u8; 4], salt: u64) -> [u8; 32] {
+        let mut nonce = [0; 12];
+        nonce[..8].copy_from_slice(&salt.to_le_bytes());
+        nonce[8..].copy_from_slice(&label);
+        let mut derived = [0; 32];
+        // the tag is of no use, only the key stream encrypting the zeros is kept
+        ChaCha20Poly1305::new(&self.key.into())
+            .encrypt_in_place_detached(Nonce::from_slice(&nonce), b"", &mut derived)
+            .expect("32 bytes are within the limit of the cipher");
+        derived
+    }
+}
+
+/// never prints the key itself
+impl fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "EncryptionKey({:016x})", self.id())
+    }
+}
+
+/// seals the records of one encrypted file
+///
+/// a sealed record is | ciphertext length u32 | ciphertext | tag |,
+/// its nonce is its offset in the file and the generation and the format byte of the file
+/// are authenticated with it, so records can not be moved around within a file nor between
+/// files, and the file can not be made to be read in another format
+pub(crate) struct FileCipher {
+    aead: ChaCha20Poly1305,
+    key_id: u64,
+    /// gen u64 | format u8
+    aad: [u8; 9],
+}
+
+impl FileCipher {
+    /// a cipher for a new file of the given generation and format byte with a fresh salt,
+    /// returned with the header to write in front of the records
+    pub(crate) fn create(
+        master: &EncryptionKey,
+        gen: u64,
+        format: u8,
+    ) -> (FileCipher, [u8; CIPHER_HEADER_LEN]) {
+        let salt = rand::random::<u64>();
+        let key_id = master.id();
+        let mut header = [0; CIPHER_HEADER_LEN];
+        header[..8].copy_from_slice(&key_id.to_le_bytes());
+        header[8..].copy_from_slice(&salt.to_le_bytes());
+        (
+            FileCipher::with_salt(master, key_id, gen, format, salt),
+            header,
+        )
+    }
+
+    /// the cipher of an existing file, the master key is picked from `keys` by the
+    /// key id in the header, `KvsError::WrongKeyError` is returned if none matches
+    pub(crate) fn from_header<'a>(
+        header: &[u8],
+        gen: u64,
+        format: u8,
+        mut keys: impl Iterator<Item = &'a EncryptionKey>,
+    ) -> Result<FileCipher> {
+        let key_id = u64_at(header, 0);
+        let master = keys
+            .find(|key| key.id() == key_id)
+            .ok_or(KvsError::WrongKeyError)?;
+        Ok(FileCipher::with_salt(
+            master,
+            key_id,
+            gen,
+            format,
+            u64_at(header, 8),
+        ))
+    }
+
+    fn with_salt(
+        master: &EncryptionKey,
+        key_id: u64,
+        gen: u64,
+        format: u8,
+        salt: u64,
+    ) -> FileCipher {
+        let mut aad = [0; 9];
+        aad[..8].copy_from_slice(&gen.to_le_bytes());
+        aad[8] = format;
+        FileCipher {
+            aead: ChaCha20Poly1305::new(&master.derive(*b"file", salt).into()),
+            key_id,
+            aad,
+        }
+    }
+
+    /// the id of the master key the file is encrypted with
+    pub(crate) fn key_id(&self) -> u64 {
+        self.key_id
+    }
+
+    /// seals the record written at the given offset of the file
+    pub(crate) fn seal(&self, offset: u64, record: &[u8]) -> Result<Vec<u8>> {
+        if record.len() > u32::MAX as usize {
+            return Err(KvsError::StringError(
+                "the record is too large for the log".to_owned(),
+            ));
+        }
+        let mut sealed = Vec::with_capacity(4 + record.len() + TAG_LEN);
+        sealed.extend_from_slice(&(record.len() as u32).to_le_bytes());
+        sealed.extend_from_slice(record);
+        let tag = self
+            .aead
+            .encrypt_in_place_detached(&nonce(offset), &self.aad, &mut sealed[4..])
+            .map_err(|_| KvsError::StringError("the record is too large to seal".to_owned()))?;
+        sealed.extend_from_slice(&tag);
+        Ok(sealed)
+    }
+
+    /// opens a record produced by `seal` at the given offset
+    pub(crate) fn open(&self, offset: u64, sealed: &[u8]) -> Result<Vec<u8>> {
+        if sealed.len() < 4 + TAG_LEN || u32_at(sealed, 0) as usize + 4 + TAG_LEN != sealed.len() {
+            return Err(KvsError::TamperedDataError);
+        }
+        let (message, tag) = sealed[4..].split_at(sealed.len() - 4 - TAG_LEN);
+        let mut message = message.to_vec();
+        self.aead
+            .decrypt_in_place_detached(
+                &nonce(offset),
+                &self.aad,
+                &mut message,
+                Tag::from_slice(tag),
+            )
+            .map_err(|_| KvsError::TamperedDataError)?;
+        Ok(message)
+    }
+
+    /// reads the next sealed record into `buf` and returns how many bytes were consumed,
+    /// `0` means the end of the file
+    pub(crate) fn read_sealed(reader: &mut impl BufRead, buf: &mut Vec<u8>) -> Result<u64> {
+        buf.clear();
+        reader.take(4).read_to_end(buf)?;
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if buf.len() < 4 {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        let rest = u32_at(buf, 0) as u64 + TAG_LEN as u64;
+        if reader.take(rest).read_to_end(buf)? as u64 != rest {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        Ok(buf.len() as u64)
+    }
+}
+
+fn nonce(offset: u64) -> Nonce {
+    let mut nonce = Nonce::default();
+    nonce[..8].copy_from_slice(&offset.to_le_bytes());
+    nonce
+}
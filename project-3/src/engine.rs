@@ -1,9 +1,406 @@
-use crate::Result;
+use crate::protocol::{CHUNK_LEN, MAX_FRAME_LEN};
+use crate::{KvsError, Result, WriteBatch};
+use std::future::Future;
+use std::io::{Cursor, Read};
+use std::ops::{Bound, RangeBounds};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, Receiver};
 
-pub trait KvsEngine {
-    fn set(&mut self, key: String, value: String) -> Result<()>;
+/// The interface of a key-value storage engine.
+/// An engine is a handle: clones share the same data and can be moved to other threads,
+/// so every method takes `&self`.
+///
+/// Keys and values are arbitrary bytes, anything turning into a `Vec<u8>` can be passed,
+/// `String`s included. The methods returning them come in two flavors: the `_bytes` one,
+/// and a `String` one which fails with `KvsError::Utf8Error` on data that is not utf-8.
+pub trait KvsEngine: Clone + Send + 'static {
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()>;
 
-    fn get(&mut self, key: String) -> Result<Option<String>>;
+    /// like `set`, but the key is gone once `ttl` has passed
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()>;
 
-    fn remove(&mut self, key: String) -> Result<()>;
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()>;
+
+    /// the value of the key as a reader, for values too large to be held in memory at once;
+    /// the engines unable to stream read the whole value first
+    fn get_reader(&self, key: impl Into<Vec<u8>>) -> Result<Option<ValueReader>> {
+        let value = self.get_bytes(key)?;
+        Ok(value.map(|value| Box::new(Cursor::new(value)) as ValueReader))
+    }
+
+    /// sets the value of the key to everything read from `value`;
+    /// the engines unable to stream read the whole value first, see `read_whole_value`
+    fn set_reader(&self, key: impl Into<Vec<u8>>, value: impl Read) -> Result<()> {
+        self.set(key, read_whole_value(value)?)
+    }
+
+    /// the pairs whose keys fall into the range, in ascending order of the keys
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter<'_>>;
+
+    /// the pairs whose keys start with the prefix, in ascending order of the keys
+    fn scan_prefix_bytes(&self, prefix: impl Into<Vec<u8>>) -> Result<BytesIter<'_>> {
+        let prefix = prefix.into();
+        Ok(take_prefix(self.scan_bytes(prefix.clone()..)?, prefix))
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvsIter<'_>> {
+        Ok(utf8_pairs(self.scan_bytes(byte_range(range))?))
+    }
+
+    fn scan_prefix(&self, prefix: String) -> Result<KvsIter<'_>> {
+        Ok(utf8_pairs(self.scan_prefix_bytes(prefix)?))
+    }
+
+    /// every key in the store, in ascending order
+    fn keys_bytes(&self) -> Result<Vec<Vec<u8>>>;
+
+    /// the keys starting with the prefix, in ascending order,
+    /// the values are not read
+    fn scan_prefix_keys(&self, prefix: impl Into<Vec<u8>>) -> Result<Vec<Vec<u8>>>;
+
+    fn keys(&self) -> Result<Vec<String>> {
+        let keys = self.keys_bytes()?.into_iter().map(String::from_utf8);
+        Ok(keys.collect::<std::result::Result<_, _>>()?)
+    }
+
+    /// applies every operation of the batch or none of them,
+    /// `KvsError::KeyNotFoundError` is returned if a removed key does not exist
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// sets the key to `new` only if its current value is `expected`, in one step;
+    /// `None` stands for a missing key in both, so `new: None` removes the key.
+    /// On a conflict nothing is written and the current value is returned in the `Err`
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>>;
+
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<std::result::Result<(), Option<String>>> {
+        let result = self.compare_and_swap_bytes(
+            key,
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )?;
+        match result {
+            Ok(()) => Ok(Ok(())),
+            Err(current) => Ok(Err(current.map(String::from_utf8).transpose()?)),
+        }
+    }
+
+    /// The read-only view returned by `snapshot`
+    type Snapshot: KvsSnapshot;
+
+    /// a view of the store as it is now, the writes made after it are not seen by it
+    fn snapshot(&self) -> Result<Self::Snapshot>;
+}
+
+/// A read-only, point-in-time view of a `KvsEngine`, see `KvsEngine::snapshot`
+pub trait KvsSnapshot: Send + 'static {
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>>;
+
+    fn get(&self, key: impl Into<Vec<u8>>) -> Result<Option<String>> {
+        Ok(self.get_bytes(key)?.map(String::from_utf8).transpose()?)
+    }
+
+    /// the pairs whose keys fall into the range, in ascending order of the keys
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter<'_>>;
+
+    /// the pairs whose keys start with the prefix, in ascending order of the keys
+    fn scan_prefix_bytes(&self, prefix: impl Into<Vec<u8>>) -> Result<BytesIter<'_>> {
+        let prefix = prefix.into();
+        Ok(take_prefix(self.scan_bytes(prefix.clone()..)?, prefix))
+    }
+
+    fn scan<R: RangeBounds<String>>(&self, range: R) -> Result<KvsIter<'_>> {
+        Ok(utf8_pairs(self.scan_bytes(byte_range(range))?))
+    }
+
+    fn scan_prefix(&self, prefix: String) -> Result<KvsIter<'_>> {
+        Ok(utf8_pairs(self.scan_prefix_bytes(prefix)?))
+    }
+}
+
+/// reads a value to be held in memory at once,
+/// one larger than a frame of the protocol is refused instead
+pub(crate) fn read_whole_value(value: impl Read) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    value.take(MAX_FRAME_LEN as u64 + 1).read_to_end(&mut buf)?;
+    if buf.len() > MAX_FRAME_LEN as usize {
+        return Err(KvsError::StringError(
+            "the value is too large to be read at once".to_owned(),
+        ));
+    }
+    Ok(buf)
+}
+
+/// the leading pairs of a scan from `prefix` whose keys start with it
+fn take_prefix(pairs: BytesIter<'_>, prefix: Vec<u8>) -> BytesIter<'_> {
+    Box::new(pairs.take_while(move |pair| match pair {
+        Ok((key, _)) => key.starts_with(&prefix),
+        Err(_) => true,
+    }))
+}
+
+/// the same range over the bytes of the keys, which sort like the strings
+fn byte_range<R: RangeBounds<String>>(range: R) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let bytes = |key: &String| key.clone().into_bytes();
+    (range.start_bound().map(bytes), range.end_bound().map(bytes))
+}
+
+fn utf8_pairs(pairs: BytesIter<'_>) -> KvsIter<'_> {
+    Box::new(pairs.map(|pair| {
+        let (key, value) = pair?;
+        Ok((String::from_utf8(key)?, String::from_utf8(value)?))
+    }))
+}
+
+/// The iterator over the key-value pairs returned by `KvsEngine::scan`
+pub type KvsIter<'a> = Box<dyn Iterator<Item = Result<(String, String)>> + 'a>;
+
+/// The iterator over the key-value pairs returned by `KvsEngine::scan_bytes`
+pub type BytesIter<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// A value read by `KvsEngine::get_reader`
+pub type ValueReader = Box<dyn Read + Send>;
+
+/// The future returned by the methods of `AsyncKvsEngine`
+pub type KvsFuture<T> = Pin<Box<dyn Future<Output = Result<T>> + Send>>;
+
+/// The asynchronous interface of a key-value storage engine, used by the tokio server.
+///
+/// A `KvsEngine` is used through `Blocking`, which runs its blocking calls on
+/// the blocking threads of the tokio runtime, so the methods have to be
+/// called within a runtime.
+pub trait AsyncKvsEngine: Clone + Send + 'static {
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> KvsFuture<()>;
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> KvsFuture<()>;
+
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> KvsFuture<Option<Vec<u8>>>;
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> KvsFuture<()>;
+
+    /// `KvsEngine::get_reader`, the value comes in chunks of at most `CHUNK_LEN` bytes
+    fn get_chunks(&self, key: impl Into<Vec<u8>>) -> KvsFuture<Option<Receiver<Result<Vec<u8>>>>>;
+
+    /// `KvsEngine::set_reader`, the value is the chunks received up to an empty one,
+    /// the set fails if the sender is dropped before it
+    fn set_chunks(&self, key: impl Into<Vec<u8>>, chunks: Receiver<Vec<u8>>) -> KvsFuture<()>;
+
+    fn write_batch(&self, batch: WriteBatch) -> KvsFuture<()>;
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvsFuture<std::result::Result<(), Option<Vec<u8>>>>;
+
+    /// the first `limit` pairs of `KvsEngine::scan_bytes`
+    fn scan_bytes(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        limit: usize,
+    ) -> KvsFuture<Vec<(Vec<u8>, Vec<u8>)>>;
+
+    /// the first `limit` pairs of `KvsEngine::scan_prefix_bytes`
+    fn scan_prefix_bytes(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+        limit: usize,
+    ) -> KvsFuture<Vec<(Vec<u8>, Vec<u8>)>>;
+}
+
+/// A `KvsEngine` used as an `AsyncKvsEngine`.
+///
+/// The adapter keeps the methods of the two traits apart,
+/// so both can be in scope at once.
+#[derive(Clone)]
+pub struct Blocking<E>(E);
+
+impl<E: KvsEngine> Blocking<E> {
+    pub fn new(engine: E) -> Blocking<E> {
+        Blocking(engine)
+    }
+
+    /// the engine the calls are run on
+    pub fn engine(&self) -> &E {
+        &self.0
+    }
+}
+
+impl<E: KvsEngine> AsyncKvsEngine for Blocking<E> {
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> KvsFuture<()> {
+        let engine = self.0.clone();
+        let (key, value) = (key.into(), value.into());
+        blocking(move || engine.set(key, value))
+    }
+
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> KvsFuture<()> {
+        let engine = self.0.clone();
+        let (key, value) = (key.into(), value.into());
+        blocking(move || engine.set_with_ttl(key, value, ttl))
+    }
+
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> KvsFuture<Option<Vec<u8>>> {
+        let engine = self.0.clone();
+        let key = key.into();
+        blocking(move || engine.get_bytes(key))
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> KvsFuture<()> {
+        let engine = self.0.clone();
+        let key = key.into();
+        blocking(move || engine.remove(key))
+    }
+
+    fn get_chunks(&self, key: impl Into<Vec<u8>>) -> KvsFuture<Option<Receiver<Result<Vec<u8>>>>> {
+        let engine = self.0.clone();
+        let key = key.into();
+        blocking(move || {
+            let mut value = match engine.get_reader(key)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let (sender, receiver) = mpsc::channel(CHANNEL_CHUNKS);
+            // 另起一个阻塞任务读取, 接收端被丢弃时停止
+            tokio::task::spawn_blocking(move || loop {
+                let mut chunk = vec![0; CHUNK_LEN];
+                let chunk = match value.read(&mut chunk) {
+                    Ok(0) => return,
+                    Ok(len) => {
+                        chunk.truncate(len);
+                        Ok(chunk)
+                    }
+                    Err(e) => Err(e.into()),
+                };
+                let failed = chunk.is_err();
+                if sender.blocking_send(chunk).is_err() || failed {
+                    return;
+                }
+            });
+            Ok(Some(receiver))
+        })
+    }
+
+    fn set_chunks(&self, key: impl Into<Vec<u8>>, chunks: Receiver<Vec<u8>>) -> KvsFuture<()> {
+        let engine = self.0.clone();
+        let key = key.into();
+        blocking(move || {
+            let chunks = ReceiverReader {
+                receiver: chunks,
+                chunk: Cursor::new(Vec::new()),
+                done: false,
+            };
+            engine.set_reader(key, chunks)
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> KvsFuture<()> {
+        let engine = self.0.clone();
+        blocking(move || engine.write_batch(batch))
+    }
+
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> KvsFuture<std::result::Result<(), Option<Vec<u8>>>> {
+        let engine = self.0.clone();
+        let key = key.into();
+        blocking(move || engine.compare_and_swap_bytes(key, expected, new))
+    }
+
+    fn scan_bytes(
+        &self,
+        range: (Bound<Vec<u8>>, Bound<Vec<u8>>),
+        limit: usize,
+    ) -> KvsFuture<Vec<(Vec<u8>, Vec<u8>)>> {
+        let engine = self.0.clone();
+        blocking(move || engine.scan_bytes(range)?.take(limit).collect())
+    }
+
+    fn scan_prefix_bytes(
+        &self,
+        prefix: impl Into<Vec<u8>>,
+        limit: usize,
+    ) -> KvsFuture<Vec<(Vec<u8>, Vec<u8>)>> {
+        let engine = self.0.clone();
+        let prefix = prefix.into();
+        blocking(move || engine.scan_prefix_bytes(prefix)?.take(limit).collect())
+    }
+}
+
+/// how many chunks of a streamed value wait in a channel
+const CHANNEL_CHUNKS: usize = 4;
+
+/// reads the chunks sent through the channel, blocking the thread while waiting
+struct ReceiverReader {
+    receiver: Receiver<Vec<u8>>,
+    chunk: Cursor<Vec<u8>>,
+    /// whether the empty chunk ending the value was received
+    done: bool,
+}
+
+impl Read for ReceiverReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            let len = self.chunk.read(buf)?;
+            if len > 0 || buf.is_empty() {
+                return Ok(len);
+            }
+            if self.done {
+                return Ok(0);
+            }
+            match self.receiver.blocking_recv() {
+                Some(chunk) => {
+                    self.done = chunk.is_empty();
+                    self.chunk = Cursor::new(chunk);
+                }
+                None => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            }
+        }
+    }
+}
+
+fn blocking<T, F>(f: F) -> KvsFuture<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> Result<T> + Send + 'static,
+{
+    Box::pin(async move {
+        tokio::task::spawn_blocking(f)
+            .await
+            .map_err(|e| KvsError::StringError(format!("engine task failed: {}", e)))?
+    })
 }
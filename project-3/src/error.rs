@@ -1,4 +1,6 @@
 // use failure;
+// `failure_derive` expands into impls nested in a constant
+#![allow(non_local_definitions)]
 use failure_derive::Fail;
 
 /// Error type for kvs
@@ -13,10 +15,29 @@ pub enum KvsError {
     /// caused by key not found
     #[fail(display = "Key not found")]
     KeyNotFoundError,
+    /// caused by a log record that does not match its checksum or cannot be decoded
+    #[fail(display = "Corrupted log record")]
+    CorruptedLogError,
     #[fail(display = "Wrong database engine")]
     WrongEngineError,
     #[fail(display = "{}", _0)]
     SledError(#[cause] sled::Error),
+    /// caused by a thread pool failing to start
+    #[fail(display = "{}", _0)]
+    ThreadPoolError(#[cause] rayon::ThreadPoolBuildError),
+    /// caused by reading a key or a value that is not valid utf-8 as a `String`
+    #[fail(display = "{}", _0)]
+    Utf8Error(#[cause] std::string::FromUtf8Error),
+    /// caused by encrypted data files whose key was not given
+    #[fail(display = "Wrong encryption key")]
+    WrongKeyError,
+    /// caused by encrypted data that fails authentication,
+    /// or data in plain text where it should be encrypted
+    #[fail(display = "Encrypted data has been tampered with")]
+    TamperedDataError,
+    /// caused by a failure reported by the server or a malformed message
+    #[fail(display = "{}", _0)]
+    StringError(String),
 }
 
 impl From<std::io::Error> for KvsError {
@@ -37,5 +58,27 @@ impl From<sled::Error> for KvsError {
     }
 }
 
+impl From<rayon::ThreadPoolBuildError> for KvsError {
+    fn from(inner: rayon::ThreadPoolBuildError) -> KvsError {
+        KvsError::ThreadPoolError(inner)
+    }
+}
+
+impl From<std::string::FromUtf8Error> for KvsError {
+    fn from(inner: std::string::FromUtf8Error) -> KvsError {
+        KvsError::Utf8Error(inner)
+    }
+}
+
+/// for the errors met inside the `Read` implementations of the streamed values
+impl From<KvsError> for std::io::Error {
+    fn from(e: KvsError) -> std::io::Error {
+        match e {
+            KvsError::IoError(inner) => inner,
+            e => std::io::Error::other(e.to_string()),
+        }
+    }
+}
+
 /// Result type for kvs
 pub type Result<T> = std::result::Result<T, KvsError>;
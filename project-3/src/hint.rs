@@ -0,0 +1,134 @@
+use crate::crypto::{FileCipher, CIPHER_HEADER_LEN};
+use crate::kv::LogInFile;
+use crate::log_format::{u32_at, u64_at};
+use crate::{EncryptionKey, KvsError, Result};
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// A hint file is written next to the segment produced by a compaction.
+// It holds the place of every live log in that segment, so `open` can fill the
+// index without decoding the segment.
+//
+// | magic | entries ... | checksum u32 |
+//
+// an entry is | key length u32 | key | gen u64 | offset u64 | length u64 | expires u64 |
+// value length u64 | stored value length u64 |,
+// integers are little endian and the checksum covers everything before itself,
+// an expiry of 0 means the value never expires.
+// Hint files of older versions carry no expiry or value lengths, their magic no longer
+// matches so the segments are replayed instead.
+// The hint file of an encrypted store is sealed as a whole, behind its own magic
+// and the header of its cipher.
+
+/// magic bytes at the beginning of every hint file
+const HINT_MAGIC: &[u8] = b"KVSHINT\x03";
+
+/// magic bytes at the beginning of an encrypted hint file
+const ENCRYPTED_HINT_MAGIC: &[u8] = b"KVSHINT\x83";
+
+/// authenticated by the cipher of an encrypted hint file in place of the format byte
+/// of a segment
+const HINT_CIPHER_TAG: u8 = 0x83;
+
+/// gen, offset, length, expires and the two value lengths
+const ENTRY_LEN: usize = 48;
+
+pub(crate) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
+
+/// writes the hint file of the given generation, encrypted if a key is given,
+/// it only shows up under its final name once it is completely on disk
+pub(crate) fn write_hint<'a>(
+    dir: &Path,
+    gen: u64,
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a LogInFile)>,
+    encryption_key: Option<&EncryptionKey>,
+) -> Result<()> {
+    let mut buf = HINT_MAGIC.to_vec();
+    for (key, log) in entries {
+        buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+        buf.extend_from_slice(key);
+        buf.extend_from_slice(&log.gen.to_le_bytes());
+        buf.extend_from_slice(&log.offset.to_le_bytes());
+        buf.extend_from_slice(&log.length.to_le_bytes());
+        buf.extend_from_slice(&log.expires.unwrap_or(0).to_le_bytes());
+        buf.extend_from_slice(&log.value_len.to_le_bytes());
+        buf.extend_from_slice(&log.stored_value_len.to_le_bytes());
+    }
+    let checksum = crc32fast::hash(&buf);
+    buf.extend_from_slice(&checksum.to_le_bytes());
+    if let Some(encryption_key) = encryption_key {
+        let (cipher, header) = FileCipher::create(encryption_key, gen, HINT_CIPHER_TAG);
+        let mut sealed = ENCRYPTED_HINT_MAGIC.to_vec();
+        sealed.extend_from_slice(&header);
+        let offset = sealed.len() as u64;
+        sealed.extend(cipher.seal(offset, &buf)?);
+        buf = sealed;
+    }
+
+    let tmp_path = dir.join(format!("{}.hint.tmp", gen));
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(&buf)?;
+    file.sync_all()?;
+    drop(file);
+
+    fs::rename(tmp_path, hint_path(dir, gen))?;
+    Ok(())
+}
+
+/// reads the hint file of the given generation, decrypting it with one of `keys`,
+/// a `KvsError::CorruptedLogError` is returned if it does not match its checksum
+/// and a `KvsError::TamperedDataError` if it is in plain text but `plaintext` is false
+pub(crate) fn read_hint<'a>(
+    dir: &Path,
+    gen: u64,
+    keys: impl Iterator<Item = &'a EncryptionKey>,
+    plaintext: bool,
+) -> Result<Vec<(Vec<u8>, LogInFile)>> {
+    let mut buf = fs::read(hint_path(dir, gen))?;
+    if !plaintext && !buf.starts_with(ENCRYPTED_HINT_MAGIC) {
+        return Err(KvsError::TamperedDataError);
+    }
+    if buf.starts_with(ENCRYPTED_HINT_MAGIC) {
+        let offset = ENCRYPTED_HINT_MAGIC.len() + CIPHER_HEADER_LEN;
+        if buf.len() < offset {
+            return Err(KvsError::CorruptedLogError);
+        }
+        let header = &buf[ENCRYPTED_HINT_MAGIC.len()..];
+        let cipher = FileCipher::from_header(header, gen, HINT_CIPHER_TAG, keys)?;
+        buf = cipher.open(offset as u64, &buf[offset..])?;
+    }
+    if buf.len() < HINT_MAGIC.len() + 4 || !buf.starts_with(HINT_MAGIC) {
+        return Err(KvsError::CorruptedLogError);
+    }
+    let (body, checksum) = buf.split_at(buf.len() - 4);
+    if crc32fast::hash(body) != u32_at(checksum, 0) {
+        return Err(KvsError::CorruptedLogError);
+    }
+
+    let mut entries = Vec::new();
+    let mut at = HINT_MAGIC.len();
+    while at < body.len() {
+        if at + 4 > body.len() {
+            return Err(KvsError::CorruptedLogError);
+        }
+        let key_end = at + 4 + u32_at(body, at) as usize;
+        if key_end + ENTRY_LEN > body.len() {
+            return Err(KvsError::CorruptedLogError);
+        }
+        let key = body[at + 4..key_end].to_vec();
+        let expires = Some(u64_at(body, key_end + 24)).filter(|&expires| expires != 0);
+        let log = LogInFile::new(
+            u64_at(body, key_end),
+            u64_at(body, key_end + 8),
+            u64_at(body, key_end + 16),
+        )
+        .expiring(expires)
+        .holding(u64_at(body, key_end + 32), u64_at(body, key_end + 40));
+        entries.push((key, log));
+        at = key_end + ENTRY_LEN;
+    }
+    Ok(entries)
+}
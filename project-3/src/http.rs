@@ -0,0 +1,187 @@
+//! an HTTP/JSON gateway in front of any `KvsEngine`
+//!
+//! | request                | response                                             |
+//! |------------------------|------------------------------------------------------|
+//! | `GET /keys/{key}`      | 200 `{"key": ..., "value": ...}` or 404              |
+//! | `PUT /keys/{key}`      | 204, the request body is the value, 413 over 64 MiB  |
+//! | `DELETE /keys/{key}`   | 204 or 404                                           |
+//! | `GET /keys?prefix={p}` | 200 `{"keys": [...]}`, the keys starting with p      |
+//!
+//! Keys in the path and the query are percent-encoded, errors come back as
+//! `{"error": ...}`. Keys and values are bytes: in the json they are strings
+//! when they are valid utf-8 and `{"base64": "..."}` otherwise.
+
+use crate::protocol::MAX_FRAME_LEN;
+use crate::{KvsEngine, KvsError, Result, ThreadPool};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use serde_json::{json, Value};
+use std::io::{self, ErrorKind, Read, Take};
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::Arc;
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// An HTTP server translating REST requests into calls on the engine
+pub struct HttpGateway<E: KvsEngine> {
+    store: E,
+    server: Arc<Server>,
+}
+
+impl<E: KvsEngine> HttpGateway<E> {
+    /// listens on the given address, port 0 picks a free port
+    pub fn bind(store: E, addr: impl ToSocketAddrs) -> Result<HttpGateway<E>> {
+        let server = Server::http(addr).map_err(|e| KvsError::StringError(e.to_string()))?;
+        Ok(HttpGateway {
+            store,
+            server: Arc::new(server),
+        })
+    }
+
+    /// the address the gateway is listening on
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.server.server_addr().to_ip()
+    }
+
+    /// answers requests forever, each one as a job of the pool
+    pub fn run(self, pool: impl ThreadPool) {
+        for request in self.server.incoming_requests() {
+            let store = self.store.clone();
+            pool.spawn(move || respond(&store, request));
+        }
+    }
+}
+
+fn respond(store: &impl KvsEngine, mut request: Request) {
+    let (status, body) = match route(store, &mut request) {
+        Ok((status, body)) => (status, body),
+        Err(KvsError::KeyNotFoundError) => (404, Some(json!({ "error": "Key not found" }))),
+        Err(KvsError::StringError(message)) => (400, Some(json!({ "error": message }))),
+        Err(e) => (500, Some(json!({ "error": e.to_string() }))),
+    };
+    // 客户端已经断开时没有什么可做的
+    let _ = match body {
+        Some(body) => {
+            let header = Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..])
+                .expect("a valid header");
+            request.respond(
+                Response::from_string(body.to_string())
+                    .with_status_code(status)
+                    .with_header(header),
+            )
+        }
+        None => request.respond(Response::empty(status)),
+    };
+}
+
+/// the status and the body of the response, `None` for an empty body
+fn route(store: &impl KvsEngine, request: &mut Request) -> Result<(u16, Option<Value>)> {
+    let url = request.url().to_owned();
+    let (path, query) = match url.find('?') {
+        Some(at) => (&url[..at], &url[at + 1..]),
+        None => (url.as_str(), ""),
+    };
+
+    if path == "/keys" {
+        if *request.method() != Method::Get {
+            return Ok((405, Some(json!({ "error": "Method not allowed" }))));
+        }
+        let mut prefix = Vec::new();
+        for pair in query.split('&') {
+            if let Some(value) = pair.strip_prefix("prefix=") {
+                prefix = percent_decode(value, true)?;
+            }
+        }
+        let mut keys = Vec::new();
+        for key in store.scan_prefix_keys(prefix)? {
+            keys.push(json_bytes(key));
+        }
+        return Ok((200, Some(json!({ "keys": keys }))));
+    }
+
+    let key = match path.strip_prefix("/keys/") {
+        Some(key) if !key.is_empty() => percent_decode(key, false)?,
+        _ => return Ok((404, Some(json!({ "error": "Not found" })))),
+    };
+    match request.method() {
+        Method::Get => match store.get_bytes(key.clone())? {
+            Some(value) => {
+                let body = json!({ "key": json_bytes(key), "value": json_bytes(value) });
+                Ok((200, Some(body)))
+            }
+            None => Err(KvsError::KeyNotFoundError),
+        },
+        Method::Put => {
+            if request.body_length().unwrap_or(0) > MAX_FRAME_LEN as usize {
+                return Ok((413, Some(json!({ "error": "Value too large" }))));
+            }
+            // a chunked body has no length up front, it is cut off right past the limit
+            let mut body = BoundedBody {
+                inner: request.as_reader().take(MAX_FRAME_LEN as u64 + 1),
+                too_large: false,
+            };
+            match store.set_reader(key, &mut body) {
+                Err(_) if body.too_large => Ok((413, Some(json!({ "error": "Value too large" })))),
+                result => result.map(|()| (204, None)),
+            }
+        }
+        Method::Delete => {
+            store.remove(key)?;
+            Ok((204, None))
+        }
+        _ => Ok((405, Some(json!({ "error": "Method not allowed" })))),
+    }
+}
+
+/// a request body failing the read once it goes over `MAX_FRAME_LEN`,
+/// so the engine never stores a value cut short
+struct BoundedBody<R> {
+    inner: Take<R>,
+    too_large: bool,
+}
+
+impl<R: Read> Read for BoundedBody<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        if self.inner.limit() == 0 {
+            self.too_large = true;
+            return Err(io::Error::new(ErrorKind::InvalidData, "value too large"));
+        }
+        Ok(len)
+    }
+}
+
+/// decodes `%XX` escapes, and `+` as a space in a query
+fn percent_decode(s: &str, query: bool) -> Result<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => {
+                let byte = s
+                    .get(i + 1..i + 3)
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    .ok_or_else(|| KvsError::StringError("invalid percent-encoding".to_owned()))?;
+                decoded.push(byte);
+                i += 3;
+            }
+            b'+' if query => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            b => {
+                decoded.push(b);
+                i += 1;
+            }
+        }
+    }
+    Ok(decoded)
+}
+
+/// bytes in the json of a response, a string if they are utf-8
+fn json_bytes(bytes: Vec<u8>) -> Value {
+    match String::from_utf8(bytes) {
+        Ok(text) => Value::String(text),
+        Err(e) => json!({ "base64": STANDARD.encode(e.into_bytes()) }),
+    }
+}
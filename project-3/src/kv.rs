@@ -1,201 +1,1510 @@
-use crate::KvsEngine;
+use crate::crypto::{FileCipher, CIPHER_HEADER_LEN, MIN_SEALED_LEN};
+use crate::engine::read_whole_value;
+use crate::hint::{hint_path, read_hint, write_hint};
+use crate::log_format::{binary_set_prefix, binary_value_reader, Record, ENCRYPTED_MAGIC};
+use crate::{BatchOp, Compression, EncryptionKey, KvStoreOptions, LogFormat, WriteBatch};
+use crate::{BytesIter, KvsEngine, KvsSnapshot, ValueReader};
 use crate::{KvsError, Result};
+use crc32fast::Hasher;
+use crossbeam_skiplist::SkipMap;
+use crossbeam_utils::atomic::AtomicCell;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
-use std::fs::{self, OpenOptions};
-use std::io::{BufReader, Read};
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Cursor, ErrorKind, Read};
 use std::io::{BufWriter, Write};
 use std::io::{Seek, SeekFrom};
-use std::path::PathBuf;
+use std::iter::{self, Peekable};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use structopt::StructOpt;
 
-const MAX_UNCOMPACTED_SIZE: u64 = 1024 * 1024;
+/// name of the single log file written by older versions of `KvStore`
+const LEGACY_LOG_NAME: &str = "kvs-data.json";
+
+/// magic + format + cipher header
+const ENCRYPTED_PREAMBLE_LEN: usize = ENCRYPTED_MAGIC.len() + 1 + CIPHER_HEADER_LEN;
 
 #[derive(Debug, Deserialize, Serialize, StructOpt)]
 pub enum Command {
-    Set { key: String, value: String },
-    Rm { key: String },
-    Get { key: String },
+    Set {
+        key: String,
+        value: String,
+        /// expire the key after this many seconds
+        #[structopt(long, parse(try_from_str = parse_ttl))]
+        #[serde(default)]
+        ttl: Option<Duration>,
+    },
+    Rm {
+        key: String,
+    },
+    Get {
+        key: String,
+    },
+    /// lists the pairs with keys from `start` (included) to `end` (excluded),
+    /// or the ones starting with `--prefix`
+    Scan {
+        start: Option<String>,
+        end: Option<String>,
+        #[structopt(long, conflicts_with_all = &["start", "end"])]
+        prefix: Option<String>,
+        /// return at most this many pairs
+        #[structopt(long)]
+        limit: Option<usize>,
+    },
+    /// sets the key to the new value only if its current value is the expected one,
+    /// without `--expected` the key must not exist and without `--new` it is removed
+    Cas {
+        key: String,
+        #[structopt(long)]
+        expected: Option<String>,
+        #[structopt(long)]
+        new: Option<String>,
+    },
+    /// applies the operations as one batch, `KEY=VALUE` sets the key
+    /// and a bare `KEY` removes it
+    Batch {
+        #[structopt(required = true)]
+        ops: Vec<BatchOp>,
+    },
+}
+
+/// parses a time to live given in seconds, like `30` or `0.5`
+fn parse_ttl(s: &str) -> Result<Duration> {
+    s.parse::<f64>()
+        .ok()
+        .filter(|&secs| secs > 0.0)
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| KvsError::StringError(format!("invalid ttl '{}'", s)))
 }
 
-/// the `KvStore` using a hashmap to store log in the memory
-/// log is presented by a position in the file and the length of it
+/// the `KvStore` using a lock-free skip list to store log in the memory
+/// logs are split into numbered segment files (`1.log`, `2.log`, ...),
+/// a log is presented by its segment, a position in that segment and the length of it
+///
+/// compaction runs on a background thread owned by the store,
+/// reads and writes go on while it copies the live logs,
+/// when it is triggered is configured by `KvStoreOptions`
+///
+/// a `KvStore` is a cheap handle, its clones share the same store and can be sent to
+/// other threads, every clone opens its own readers of the segments,
+/// so gets never wait for each other nor for writers
 pub struct KvStore {
-    map: HashMap<String, LogInFile>,
-    command: Option<Command>,
-    buffer: BufReader<std::fs::File>,
+    shared: Arc<Shared>,
+    readers: RefCell<HashMap<u64, Segment>>,
+    compactor: Arc<Compactor>,
+    discarded_size: u64,
+}
+
+/// the parts of the store used by both the store and its compaction thread
+struct Shared {
+    path: PathBuf,
+    options: KvStoreOptions,
+    /// overwriting a key updates its cell in place, because replacing an entry of the
+    /// skip list removes the old one first and a concurrent get would miss the key
+    map: SkipMap<Vec<u8>, AtomicCell<Version>>,
+    /// the versions replaced or removed since the oldest live snapshot, oldest first
+    history: SkipMap<Vec<u8>, Mutex<Vec<OldVersion>>>,
+    /// writers and the index swap of a compaction are serialized by this lock
+    writer: Mutex<LogWriter>,
+    /// segments older than this generation have been removed by a compaction
+    safe_point: AtomicU64,
+    /// held by the running compaction, so two of them never overlap
+    compaction: Mutex<()>,
+    /// the failure of the last background compaction, returned by the next write
+    compaction_error: Mutex<Option<KvsError>>,
+}
+
+/// appends records to the active segment
+struct LogWriter {
+    writer: BufWriter<File>,
+    /// seals the records if the store is encrypted
+    cipher: Option<FileCipher>,
+    current_gen: u64,
     position: u64,
     uncompacted_size: u64,
-    path: PathBuf,
+    live_size: u64,
+    /// the sequence number of the last write, every write or batch takes the next one
+    seq: u64,
+    /// the sequence numbers of the live snapshots, with how many snapshots share each
+    snapshots: BTreeMap<u64, usize>,
+    /// bytes of the live values once decompressed
+    value_bytes: u64,
+    /// the bytes those values take up in the log
+    stored_value_bytes: u64,
 }
 
-impl KvStore {
-    fn new(buffer: BufReader<std::fs::File>, path: PathBuf) -> KvStore {
-        KvStore {
-            map: HashMap::new(),
-            command: None,
-            buffer,
-            position: 0,
-            uncompacted_size: 0,
-            path,
+/// Statistics of a `KvStore`, see `KvStore::stats`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KvStoreStats {
+    /// bytes of the log holding the latest version of every key
+    pub live_bytes: u64,
+    /// bytes of the log the next compaction gets rid of
+    pub stale_bytes: u64,
+    /// bytes of the latest value of every key once decompressed
+    pub value_bytes: u64,
+    /// the bytes those values take up in the log
+    pub stored_value_bytes: u64,
+}
+
+impl KvStoreStats {
+    /// how many times smaller the values got by compression, `1.0` while no value is stored
+    pub fn compression_ratio(&self) -> f64 {
+        if self.stored_value_bytes == 0 {
+            return 1.0;
         }
+        self.value_bytes as f64 / self.stored_value_bytes as f64
     }
+}
 
+impl KvStore {
     /// This method is used to create a KvStore
-    /// It will read the "kvs-data.json" file in the path
-    /// initiate the key-log record in the memory.
+    /// It will load the `<gen>.hint` file left by the last compaction and replay
+    /// every `<gen>.log` segment written after it in generation order to
+    /// initiate the key-log record in the memory,
+    /// and start a new segment for the following writes.
+    /// A `kvs-data.json` left by older versions is taken over as the first segment.
+    /// A torn or corrupted tail left by a crash is truncated, see `discarded_bytes`.
+    /// New records are written in the `LogFormat::Json` format.
+    ///
+    /// Opening encrypted files without their key fails with `KvsError::WrongKeyError`,
+    /// records failing authentication with `KvsError::TamperedDataError`.
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
-        let mut path: PathBuf = path.into();
-        let path_clone = path.clone();
-        path.push("kvs-data.json");
-        let f = OpenOptions::new()
-            .read(true)
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&path)?;
-        let buffer = BufReader::new(f);
-        let mut str_buffer = String::new();
-        let mut kvstore = KvStore::new(buffer, path_clone);
-        kvstore.buffer.read_to_string(&mut str_buffer)?;
-        for s in str_buffer.split('\n').collect::<Vec<&str>>() {
-            if s.is_empty() {
-                continue;
-            }
-            let len = s.len() as u64;
-            let c: Command = serde_json::from_str(s)?;
-            match c {
-                Command::Set { key, .. } => {
-                    kvstore
-                        .map
-                        .insert(key, LogInFile::new(kvstore.position, len));
-                }
-                Command::Rm { key } => {
-                    kvstore.map.remove(&key);
+        KvStore::open_with_options(path, KvStoreOptions::new())
+    }
+
+    /// Same as `open`, but new records are written in the given format.
+    /// Segments written in another format stay readable,
+    /// and they are rewritten in the given format by the next `compact`,
+    /// so opening a json store with `LogFormat::Binary` and compacting it migrates it.
+    pub fn open_with_format(path: impl Into<PathBuf>, format: LogFormat) -> Result<KvStore> {
+        KvStoreOptions::new().format(format).open(path)
+    }
+
+    /// Same as `open`, but configured by the given options, see `KvStoreOptions`
+    pub fn open_with_options(path: impl Into<PathBuf>, options: KvStoreOptions) -> Result<KvStore> {
+        options.check()?;
+        let path: PathBuf = path.into();
+        fs::create_dir_all(&path)?;
+        // 中断的压缩留下的临时文件没有用处
+        for entry in fs::read_dir(&path)? {
+            let file = entry?.path();
+            if file.is_file() && file.extension() == Some(OsStr::new("tmp")) {
+                fs::remove_file(file)?;
+            }
+        }
+
+        let mut gen_list = sorted_gen_list(&path, "log")?;
+        let legacy_path = path.join(LEGACY_LOG_NAME);
+        if gen_list.is_empty() && legacy_path.is_file() {
+            fs::rename(&legacy_path, log_path(&path, 1))?;
+            gen_list.push(1);
+        }
+
+        let mut map = HashMap::new();
+        let mut hint_gen = sorted_gen_list(&path, "hint")?
+            .pop()
+            .filter(|gen| gen_list.contains(gen));
+        if let Some(gen) = hint_gen {
+            match read_hint(&path, gen, options.keys(), options.reads_plaintext()) {
+                Ok(entries) => map.extend(entries),
+                // 坏掉的 hint 文件不影响数据，重放全部的段即可
+                Err(KvsError::CorruptedLogError) => {
+                    fs::remove_file(hint_path(&path, gen))?;
+                    hint_gen = None;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let mut readers = HashMap::new();
+        let mut uncompacted_size = 0;
+        let mut discarded_size = 0;
+        // 有段不是用当前的密钥加密的，需要压缩一次来轮换密钥
+        let key_id = options.encryption_key.as_ref().map(EncryptionKey::id);
+        let mut rotate = false;
+        for &gen in &gen_list {
+            match hint_gen {
+                // left behind by a compaction interrupted before it removed them
+                Some(hint_gen) if gen < hint_gen => {
+                    fs::remove_file(log_path(&path, gen))?;
+                    continue;
                 }
                 _ => (),
             }
-            kvstore.position += len + "\n".len() as u64;
+            let mut segment = Segment::open(&path, gen, &options)?;
+            rotate |= segment.cipher.as_ref().map(FileCipher::key_id) != key_id;
+            if hint_gen != Some(gen) {
+                let newest = Some(&gen) == gen_list.last();
+                let (uncompacted, discarded) = load(&path, gen, &mut segment, newest, &mut map)?;
+                uncompacted_size += uncompacted;
+                discarded_size += discarded;
+            }
+            readers.insert(gen, segment);
+        }
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let (writer, position, cipher) = new_log_file(
+            &log_path(&path, current_gen),
+            current_gen,
+            options.format,
+            options.encryption_key.as_ref(),
+        )?;
+        let live_size = map.values().map(|log| log.length).sum();
+        let value_bytes = map.values().map(|log| log.value_len).sum();
+        let stored_value_bytes = map.values().map(|log| log.stored_value_len).sum();
+
+        let shared = Arc::new(Shared {
+            safe_point: AtomicU64::new(hint_gen.unwrap_or(0)),
+            path,
+            options,
+            map: map
+                .into_iter()
+                .map(|(key, log)| (key, AtomicCell::new(Version { seq: 0, log })))
+                .collect(),
+            history: SkipMap::new(),
+            writer: Mutex::new(LogWriter {
+                writer,
+                cipher,
+                current_gen,
+                position,
+                uncompacted_size,
+                live_size,
+                seq: 0,
+                snapshots: BTreeMap::new(),
+                value_bytes,
+                stored_value_bytes,
+            }),
+            compaction: Mutex::new(()),
+            compaction_error: Mutex::new(None),
+        });
+        let store = KvStore {
+            compactor: Arc::new(Compactor::spawn(Arc::clone(&shared))),
+            shared,
+            readers: RefCell::new(readers),
+            discarded_size,
+        };
+        if rotate && !store.shared.options.manual_compaction {
+            store.compactor.wake_up();
         }
-        Ok(kvstore)
+        Ok(store)
     }
-    /// this method is used to compact the log file
-    /// it will be automatically used by `rm` and `set` when uncompacted data size
-    /// exceed a fixed size
-    pub fn compact(&mut self) -> Result<()> {
-        let mut path_from = self.path.clone();
-        let mut path_to = self.path.clone();
-        path_from.push("kvs-data-compact.json");
-        path_to.push("kvs-data.json");
-        let f = OpenOptions::new()
-            .write(true)
-            .append(true)
-            .create(true)
-            .open(&path_from)?;
-        let reader = self.buffer.get_mut();
-        let new_reader = BufReader::new(f.try_clone()?);
-        let mut writer = BufWriter::new(f);
-        let mut new_offset: u64 = 0;
-        for (_key, LogInFile { offset, length }) in self.map.iter_mut() {
-            reader.seek(SeekFrom::Start(*offset))?;
-            let mut cmd = reader.take(*length);
-            std::io::copy(&mut cmd, &mut writer)?;
-            *offset = new_offset;
-            new_offset += *length + "\n".len() as u64;
-            new_offset += *length;
-        }
-        // rename 后原先的 path_to 对应的 bufreader 流就被关闭了，因此需要重新开一个
-        fs::rename(path_from, path_to)?;
-        self.uncompacted_size = 0;
-        self.position = writer.seek(SeekFrom::End(0))?;
-        self.buffer = new_reader;
+
+    /// how many bytes of torn or corrupted records were cut off the log by `open`
+    pub fn discarded_bytes(&self) -> u64 {
+        self.discarded_size
+    }
+
+    /// the sizes of the log and how well the live values are compressed
+    pub fn stats(&self) -> KvStoreStats {
+        let writer = self.shared.writer.lock().unwrap();
+        KvStoreStats {
+            live_bytes: writer.live_size,
+            stale_bytes: writer.uncompacted_size,
+            value_bytes: writer.value_bytes,
+            stored_value_bytes: writer.stored_value_bytes,
+        }
+    }
+
+    /// this method is used to compact the log files
+    /// it will be automatically run on the background compaction thread
+    /// when one of the triggers set in `KvStoreOptions` fires,
+    /// calling it directly compacts on the current thread and waits for the result
+    ///
+    /// live logs are copied into a fresh segment generation, the following writes go
+    /// to the generation after it, and only the segments older than the compaction
+    /// generation are deleted once the copy and its hint file are on disk.
+    /// Logs stored in another format than the store's one are re-encoded on the way,
+    /// every value keeps the compression it was written with.
+    /// The copies are encrypted with the current `KvStoreOptions::encryption_key`,
+    /// which rotates the key of the store, or left unencrypted if none is set.
+    pub fn compact(&self) -> Result<()> {
+        self.shared.compact()
+    }
+
+    /// reads the record stored at the given place,
+    /// `Ok(None)` is returned if a compaction removed its segment in the meantime
+    fn read(&self, log: &LogInFile) -> Result<Option<Record>> {
+        let safe_point = self.shared.safe_point.load(Ordering::SeqCst);
+        let mut readers = self.readers.borrow_mut();
+        readers.retain(|&gen, _| gen >= safe_point);
+        let segment = match readers.entry(log.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                match Segment::open(&self.shared.path, log.gen, &self.shared.options) {
+                    Ok(segment) => entry.insert(segment),
+                    Err(KvsError::IoError(ref e)) if e.kind() == ErrorKind::NotFound => {
+                        return Ok(None)
+                    }
+                    Err(e) => return Err(e),
+                }
+            }
+        };
+        segment.read(log).map(Some)
+    }
+
+    /// appends the operations to the log and applies them to the map,
+    /// then wakes up the compaction thread if there is enough to compact
+    ///
+    /// more than one operation are enclosed in batch markers, so they all land in the
+    /// log in a single write and are replayed all or not at all
+    fn write(&self, records: Vec<Record>) -> Result<()> {
+        let mut writer = self.lock_writer()?;
+        self.write_locked(&mut writer, records)
+    }
+
+    /// locks the writer, or returns the error the last compaction failed with
+    fn lock_writer(&self) -> Result<MutexGuard<'_, LogWriter>> {
+        if let Some(e) = self.shared.compaction_error.lock().unwrap().take() {
+            return Err(e);
+        }
+        // 写操作由 writer 锁串行化，读操作不需要任何锁
+        Ok(self.shared.writer.lock().unwrap())
+    }
+
+    /// `write` with the writer already locked
+    fn write_locked(&self, writer: &mut LogWriter, records: Vec<Record>) -> Result<()> {
+        // 写入之前检查要删除的 key 都存在，否则整个批次都不生效
+        let mut exists: HashMap<&[u8], bool> = HashMap::new();
+        for record in &records {
+            match record {
+                Record::Set { key, .. } => {
+                    exists.insert(key, true);
+                }
+                Record::Rm { key } => {
+                    let found = match exists.get(&key[..]) {
+                        Some(&found) => found,
+                        None => self.shared.lookup(key, LATEST).is_some(),
+                    };
+                    if !found {
+                        return Err(KvsError::KeyNotFoundError);
+                    }
+                    exists.insert(key, false);
+                }
+                Record::Begin | Record::Commit => unreachable!("markers are added by `append`"),
+            }
+        }
+        drop(exists);
+
+        let options = &self.shared.options;
+        let records: Vec<Record> = records
+            .into_iter()
+            .map(|record| record.compress(options.compression, options.compression_threshold))
+            .collect();
+
+        writer.seq += 1;
+        let logs = writer.append(options.format, &records)?;
+        for (record, log) in records.into_iter().zip(logs) {
+            self.shared.apply(writer, record, log);
+        }
+        self.maybe_compact(writer);
         Ok(())
     }
+
+    /// wakes up the compaction thread if there is enough to compact
+    fn maybe_compact(&self, writer: &LogWriter) {
+        if self
+            .shared
+            .options
+            .should_compact(writer.uncompacted_size, writer.live_size)
+        {
+            self.compactor.wake_up();
+        }
+    }
+}
+
+/// reads the latest version of every key
+const LATEST: u64 = u64::MAX;
+
+/// the size of the reads spooling a streamed value
+const SPOOL_BUF_LEN: usize = 64 * 1024;
+
+impl KvStore {
+    /// the value of the key as of the write numbered `seq`
+    fn get_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        loop {
+            let log = match self.shared.lookup(key, seq) {
+                None => return Ok(None),
+                Some(log) => log,
+            };
+            // 读的过程中段被压缩删掉了，就重新查一次索引
+            match self.read(&log)? {
+                None => continue,
+                Some(Record::Set { value, .. }) => return Ok(Some(value)),
+                Some(_) => return Ok(None),
+            }
+        }
+    }
+
+    /// the pairs of the keys as of the write numbered `seq`, keys without a value are skipped
+    fn scan_at<'a>(&'a self, keys: impl Iterator<Item = Vec<u8>> + 'a, seq: u64) -> BytesIter<'a> {
+        Box::new(keys.filter_map(move |key| match self.get_at(&key, seq) {
+            Ok(Some(value)) => Some(Ok((key, value))),
+            Ok(None) => None,
+            Err(e) => Some(Err(e)),
+        }))
+    }
+}
+
+impl Clone for KvStore {
+    /// the clone shares the store, but opens its own readers when it needs them
+    fn clone(&self) -> KvStore {
+        KvStore {
+            shared: Arc::clone(&self.shared),
+            readers: RefCell::new(HashMap::new()),
+            compactor: Arc::clone(&self.compactor),
+            discarded_size: self.discarded_size,
+        }
+    }
 }
 
 impl KvsEngine for KvStore {
     /// This method used to set a new key-value pair,
     /// It can also be used to update the value of a key
     /// An `KvsError::IoError` or `KvsError::SerdeError` may return
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        let key_clone = key.clone();
-        self.command = Some(Command::Set { key, value });
-        let j = serde_json::to_string(&self.command)?;
-        let len = (j.len() + "\n".len()) as u64;
-        self.map
-            .insert(key_clone, LogInFile::new(self.position, len));
-        self.position += len;
-        let mut f = self.buffer.get_ref();
-        serde_json::to_writer(f, &self.command)?;
-        // Question: using `%` to separate commands can not pass the get_stored_key test
-        f.write(b"\n")?;
-        self.uncompacted_size += len;
-        if self.uncompacted_size > MAX_UNCOMPACTED_SIZE {
-            self.compact()?;
-        }
-        Ok(())
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        self.write(vec![Record::Set {
+            key: key.into(),
+            value: value.into(),
+            expires: None,
+            compression: Compression::None,
+        }])
+    }
+
+    /// The expiry is stored in the log record,
+    /// expired logs are left out by the next compaction
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.write(vec![Record::Set {
+            key: key.into(),
+            value: value.into(),
+            expires: Some(expiry(ttl)),
+            compression: Compression::None,
+        }])
     }
 
     /// This method used to get a value of the key in the Option.
     /// Key not been set will return `Ok(None)`
     /// An `KvsError::IoError` or `KvsError::SerdeError` may return
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        match self.map.get(&key) {
-            None => Ok(None),
-            Some(log) => {
-                let reader = self.buffer.get_mut();
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        self.get_at(&key.into(), LATEST)
+    }
+
+    /// This method used to remove a key-value pair
+    /// if the given key is not exist, a `KvsError::KeyNotFoundError` will be returned
+    /// An `KvsError::IoError` or `KvsError::SerdeError` may return
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        self.write(vec![Record::Rm { key: key.into() }])
+    }
+
+    /// Values in the binary format are read straight from the log and checked against
+    /// their checksum once read to the end, values in the json format or in encrypted
+    /// segments are read whole
+    fn get_reader(&self, key: impl Into<Vec<u8>>) -> Result<Option<ValueReader>> {
+        let key = key.into();
+        loop {
+            let log = match self.shared.lookup(&key, LATEST) {
+                None => return Ok(None),
+                Some(log) => log,
+            };
+            // 每个值用自己的文件句柄读, 段被压缩删掉之后也能读完
+            let file = match File::open(log_path(&self.shared.path, log.gen)) {
+                Ok(file) => file,
+                Err(e) if e.kind() == ErrorKind::NotFound => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let mut reader = BufReader::new(file);
+            let (format, cipher, _) = read_header(&mut reader, log.gen, &self.shared.options)?;
+            if format == LogFormat::Binary && cipher.is_none() {
                 reader.seek(SeekFrom::Start(log.offset))?;
-                let cmd = reader.take(log.length);
-                if let Command::Set { value, .. } = serde_json::from_reader(cmd)? {
-                    Ok(Some(value))
-                } else {
-                    Ok(None)
+                return binary_value_reader(reader.take(log.length)).map(Some);
+            }
+            match self.read(&log)? {
+                None => continue,
+                Some(Record::Set { value, .. }) => return Ok(Some(Box::new(Cursor::new(value)))),
+                Some(_) => return Ok(None),
+            }
+        }
+    }
+
+    /// In the binary format the value is spooled to an anonymous temporary file first,
+    /// so the writer is only locked while it is copied into the log, and stored raw;
+    /// in the json format or in an encrypted store the value is read whole,
+    /// see `read_whole_value`
+    fn set_reader(&self, key: impl Into<Vec<u8>>, mut value: impl Read) -> Result<()> {
+        let key = key.into();
+        let options = &self.shared.options;
+        if options.format != LogFormat::Binary || options.encryption_key.is_some() {
+            return self.set(key, read_whole_value(value)?);
+        }
+        let mut spool = tempfile::tempfile_in(&self.shared.path)?;
+        let mut checksum = Hasher::new();
+        let mut value_len = 0;
+        let mut buf = vec![0; SPOOL_BUF_LEN];
+        loop {
+            let len = match value.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e.into()),
+            };
+            checksum.update(&buf[..len]);
+            spool.write_all(&buf[..len])?;
+            value_len += len as u64;
+        }
+        spool.seek(SeekFrom::Start(0))?;
+        let prefix = binary_set_prefix(&key, value_len, &checksum, None)?;
+
+        let mut writer = self.lock_writer()?;
+        writer.seq += 1;
+        let log = writer.append_value(&prefix, spool, value_len)?;
+        // only the key of the record is used to apply it
+        let record = Record::Set {
+            key,
+            value: Vec::new(),
+            expires: None,
+            compression: Compression::None,
+        };
+        self.shared.apply(&mut writer, record, log);
+        self.maybe_compact(&writer);
+        Ok(())
+    }
+
+    /// The batch is appended to the log in a single write,
+    /// gets running at the same time may see a part of it applied
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        self.write(batch.into_iter().map(Record::from).collect())
+    }
+
+    /// The writer lock is held from reading the current value to writing the new one
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let key = key.into();
+        let mut writer = self.lock_writer()?;
+        let current = self.get_at(&key, LATEST)?;
+        if current != expected {
+            return Ok(Err(current));
+        }
+        match new {
+            Some(value) => {
+                let set = Record::Set {
+                    key,
+                    value,
+                    expires: None,
+                    compression: Compression::None,
+                };
+                self.write_locked(&mut writer, vec![set])?
+            }
+            None if current.is_some() => {
+                self.write_locked(&mut writer, vec![Record::Rm { key }])?
+            }
+            None => (),
+        }
+        Ok(Ok(()))
+    }
+
+    /// The values are read while iterating, a pair removed in the meantime is skipped
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let keys = self
+            .shared
+            .map
+            .range(range)
+            .map(|entry| entry.key().clone());
+        Ok(self.scan_at(keys, LATEST))
+    }
+
+    type Snapshot = KvStoreSnapshot;
+
+    /// The snapshot only pins the versions it can see,
+    /// compaction keeps them and drops them once the snapshot is dropped
+    fn snapshot(&self) -> Result<KvStoreSnapshot> {
+        let mut writer = self.shared.writer.lock().unwrap();
+        let seq = writer.seq;
+        *writer.snapshots.entry(seq).or_insert(0) += 1;
+        Ok(KvStoreSnapshot {
+            store: self.clone(),
+            seq,
+        })
+    }
+
+    /// The keys come from the index, no log is read
+    fn keys_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        let now = unix_millis();
+        Ok(self
+            .shared
+            .map
+            .iter()
+            .filter(|entry| !entry.value().load().log.expired(now))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+
+    /// The keys come from the index, no log is read
+    fn scan_prefix_keys(&self, prefix: impl Into<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let prefix = prefix.into();
+        let now = unix_millis();
+        Ok(self
+            .shared
+            .map
+            .range(prefix.clone()..)
+            .take_while(|entry| entry.key().starts_with(&prefix))
+            .filter(|entry| !entry.value().load().log.expired(now))
+            .map(|entry| entry.key().clone())
+            .collect())
+    }
+}
+
+impl Shared {
+    /// applies a record just appended to the log to the map
+    fn apply(&self, writer: &mut LogWriter, record: Record, log: LogInFile) {
+        match record {
+            Record::Set { key, .. } => {
+                writer.add_live(&log);
+                let version = Version {
+                    seq: writer.seq,
+                    log,
+                };
+                match self.map.get(&key) {
+                    Some(entry) => {
+                        // 写操作是串行的, 先保留旧版本再替换
+                        let old = entry.value().load();
+                        self.keep_version(writer, &key, old, false);
+                        entry.value().store(version);
+                        writer.uncompacted_size += old.log.length;
+                        writer.remove_live(&old.log);
+                    }
+                    None => {
+                        self.map.insert(key, AtomicCell::new(version));
+                    }
+                }
+            }
+            Record::Rm { key } => {
+                if let Some(entry) = self.map.get(&key) {
+                    let old = entry.value().load();
+                    self.keep_version(writer, &key, old, true);
+                    entry.remove();
+                    writer.uncompacted_size += old.log.length;
+                    writer.remove_live(&old.log);
                 }
+                // the `rm` log itself is useless after compaction
+                writer.uncompacted_size += log.length;
             }
+            Record::Begin | Record::Commit => unreachable!("markers are not applied"),
         }
     }
 
-    /// This method used to remove a key-value pair
-    /// if the given key is not exist, a `KvsError::KeyNotFoundError` will be returned
-    /// An `KvsError::IoError` or `KvsError::SerdeError` may return
-    fn remove(&mut self, key: String) -> Result<()> {
-        match self.map.get(&key) {
-            None => return Err(KvsError::KeyNotFoundError),
-            Some(_) => {
-                self.map.remove(&key);
-                self.command = Some(Command::Rm { key });
-                let j = serde_json::to_string(&self.command)?;
-                let len = (j.len() + "\n".len()) as u64;
-                self.position += len;
-                self.uncompacted_size += len;
-                let mut f = self.buffer.get_ref();
-                serde_json::to_writer(f, &self.command)?;
-                f.write(b"\n")?;
-                if self.uncompacted_size > MAX_UNCOMPACTED_SIZE {
-                    self.compact()?;
+    /// where the value of the key as of the write numbered `seq` is stored
+    /// `None` if the key does not exist or its value has expired
+    fn lookup(&self, key: &[u8], seq: u64) -> Option<LogInFile> {
+        let now = unix_millis();
+        if let Some(entry) = self.map.get(key) {
+            let version = entry.value().load();
+            if version.seq <= seq {
+                return Some(version.log).filter(|log| !log.expired(now));
+            }
+        }
+        // 快照之后被覆盖或删除的 key, 旧版本在写入新版本之前已经放进了 history
+        let entry = self.history.get(key)?;
+        let versions = entry.value().lock().unwrap();
+        versions
+            .iter()
+            .rev()
+            .find(|(version_seq, _)| *version_seq <= seq)
+            .and_then(|(_, log)| *log)
+            .filter(|log| !log.expired(now))
+    }
+
+    /// moves the version replaced by the current write into the history
+    /// if a live snapshot can still see it
+    fn keep_version(&self, writer: &LogWriter, key: &[u8], old: Version, removed: bool) {
+        let visible = writer.snapshots.range(old.seq..).next().is_some();
+        // older versions of a removed key are hidden by the remove from later snapshots
+        let hides_history = removed && self.history.contains_key(key);
+        if !(visible || hides_history) {
+            return;
+        }
+        let entry = self
+            .history
+            .get_or_insert_with(key.to_vec(), || Mutex::new(Vec::new()));
+        let mut versions = entry.value().lock().unwrap();
+        if visible {
+            versions.push((old.seq, Some(old.log)));
+        }
+        if removed {
+            versions.push((writer.seq, None));
+        }
+    }
+
+    /// drops the versions of the history no live snapshot can see
+    fn prune_history(&self, writer: &LogWriter) {
+        for entry in self.history.iter() {
+            let latest = self
+                .map
+                .get(entry.key())
+                .map_or(LATEST, |entry| entry.value().load().seq);
+            let mut versions = entry.value().lock().unwrap();
+            // a version is seen by the snapshots taken before the version replacing it
+            let replaced_at = versions
+                .iter()
+                .skip(1)
+                .map(|(seq, _)| *seq)
+                .chain(iter::once(latest));
+            let mut kept: Vec<OldVersion> = versions
+                .iter()
+                .zip(replaced_at)
+                .filter(|((seq, _), replaced_at)| {
+                    writer.snapshots.range(*seq..*replaced_at).next().is_some()
+                })
+                .map(|(version, _)| *version)
+                .collect();
+            if latest == LATEST && kept.last().is_some_and(|(_, log)| log.is_some()) {
+                kept.push(*versions.last().unwrap());
+            }
+            let empty = kept.is_empty();
+            *versions = kept;
+            drop(versions);
+            if empty {
+                entry.remove();
+            }
+        }
+    }
+
+    /// switches the writer to a fresh segment and copies every live log
+    /// into the segment right before it, see `KvStore::compact`
+    fn compact(&self) -> Result<()> {
+        let _compaction = self.compaction.lock().unwrap();
+        let format = self.options.format;
+        let key = self.options.encryption_key.as_ref();
+
+        let (compaction_gen, live) = {
+            let mut writer = self.writer.lock().unwrap();
+            let compaction_gen = writer.current_gen + 1;
+            let (new_writer, position, cipher) = new_log_file(
+                &log_path(&self.path, compaction_gen + 1),
+                compaction_gen + 1,
+                format,
+                key,
+            )?;
+            writer.writer = new_writer;
+            writer.cipher = cipher;
+            writer.current_gen = compaction_gen + 1;
+            writer.position = position;
+            writer.uncompacted_size = 0;
+            // the old versions live snapshots can see are copied too
+            self.prune_history(&writer);
+            let now = unix_millis();
+            // `None` is a removal seen by a snapshot, copied as an `rm` record
+            let mut live: Vec<(Vec<u8>, u64, Option<LogInFile>, bool)> = Vec::new();
+            for entry in self.map.iter() {
+                let version = entry.value().load();
+                if !version.log.expired(now) {
+                    live.push((entry.key().clone(), version.seq, Some(version.log), true));
+                    continue;
+                }
+                // 过期的 key 不再复制，直接从索引里删掉；
+                // 快照看到的更早的版本要被它挡住
+                if let Some(history) = self.history.get(entry.key()) {
+                    history.value().lock().unwrap().push((version.seq, None));
+                }
+                writer.remove_live(&version.log);
+                entry.remove();
+            }
+            for entry in self.history.iter() {
+                for (seq, log) in entry.value().lock().unwrap().iter() {
+                    match log {
+                        Some(log) if log.expired(now) => (),
+                        log => live.push((entry.key().clone(), *seq, *log, false)),
+                    }
+                }
+            }
+            // 按写入的顺序复制，没有 hint 文件时重放压缩段也会得到最新的版本
+            live.sort_by_key(|(_, seq, _, _)| *seq);
+            (compaction_gen, live)
+        };
+
+        // 复制期间不持有任何锁，读写可以继续进行；
+        // 写完之前压缩段不叫 `.log`，崩溃时留下的半截段不会被重放
+        let compaction_path = tmp_path(&log_path(&self.path, compaction_gen));
+        let (mut compaction_writer, mut new_offset, cipher) =
+            new_log_file(&compaction_path, compaction_gen, format, key)?;
+        let mut readers: HashMap<u64, Segment> = HashMap::new();
+        let mut moved = Vec::with_capacity(live.len());
+        let mut removals_len = 0;
+        for (key, seq, log, latest) in live {
+            let log = match log {
+                Some(log) => log,
+                None => {
+                    let record = format.encode(&Record::Rm { key })?;
+                    let record = seal(cipher.as_ref(), new_offset, record)?;
+                    compaction_writer.write_all(&record)?;
+                    new_offset += record.len() as u64;
+                    removals_len += record.len() as u64;
+                    continue;
+                }
+            };
+            let segment = match readers.entry(log.gen) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    entry.insert(Segment::open(&self.path, log.gen, &self.options)?)
                 }
+            };
+            let mut record = segment.read_plain(&log)?;
+            if segment.format != format {
+                record = format.encode(&segment.format.decode(&record)?)?;
+            }
+            let record = seal(cipher.as_ref(), new_offset, record)?;
+            compaction_writer.write_all(&record)?;
+            let new_log = LogInFile {
+                gen: compaction_gen,
+                offset: new_offset,
+                length: record.len() as u64,
+                ..log
+            };
+            new_offset += new_log.length;
+            moved.push((key, seq, log, new_log, latest));
+        }
+        compaction_writer.flush()?;
+        compaction_writer.get_ref().sync_all()?;
+        drop(compaction_writer);
+        fs::rename(&compaction_path, log_path(&self.path, compaction_gen))?;
+        write_hint(
+            &self.path,
+            compaction_gen,
+            moved
+                .iter()
+                .filter(|(_, _, _, _, latest)| *latest)
+                .map(|(key, _, _, log, _)| (key, log)),
+            key,
+        )?;
+
+        // 持有 writer 锁切换索引，复制期间被覆盖或删除的 key 保持新的状态；
+        // 切换过程中读到旧位置也没关系，旧的段要等切换完成后才删除
+        {
+            let mut writer = self.writer.lock().unwrap();
+            writer.uncompacted_size += removals_len;
+            for (key, seq, old_log, new_log, _) in moved {
+                let old = Version { seq, log: old_log };
+                let new = Version { seq, log: new_log };
+                match self.map.get(&key) {
+                    Some(entry) if entry.value().compare_exchange(old, new).is_ok() => {
+                        writer.live_size = writer.live_size + new_log.length - old_log.length;
+                    }
+                    // the copy is stale already, unless a snapshot still reads it
+                    _ => {
+                        if let Some(entry) = self.history.get(&key) {
+                            for version in entry.value().lock().unwrap().iter_mut() {
+                                if *version == (seq, Some(old_log)) {
+                                    version.1 = Some(new_log);
+                                }
+                            }
+                        }
+                        writer.uncompacted_size += new_log.length;
+                    }
+                }
+            }
+        }
+        self.safe_point.store(compaction_gen, Ordering::SeqCst);
+
+        // 只删除比压缩段更旧的段，新写入的段保持不变
+        drop(readers);
+        for gen in sorted_gen_list(&self.path, "log")? {
+            if gen < compaction_gen {
+                fs::remove_file(log_path(&self.path, gen))?;
+            }
+        }
+        for gen in sorted_gen_list(&self.path, "hint")? {
+            if gen < compaction_gen {
+                fs::remove_file(hint_path(&self.path, gen))?;
             }
         }
         Ok(())
     }
 }
 
-struct LogInFile {
-    offset: u64,
-    length: u64,
+impl LogWriter {
+    /// counts the log of a live value into the stats
+    fn add_live(&mut self, log: &LogInFile) {
+        self.live_size += log.length;
+        self.value_bytes += log.value_len;
+        self.stored_value_bytes += log.stored_value_len;
+    }
+
+    /// takes the log of a value that is no longer live out of the stats
+    fn remove_live(&mut self, log: &LogInFile) {
+        self.live_size -= log.length;
+        self.value_bytes -= log.value_len;
+        self.stored_value_bytes -= log.stored_value_len;
+    }
+
+    /// appends the records to the active segment in one write
+    /// and returns the logs describing them,
+    /// more than one record are enclosed in `Begin` and `Commit`
+    fn append(&mut self, format: LogFormat, records: &[Record]) -> Result<Vec<LogInFile>> {
+        let batch = records.len() > 1;
+        let cipher = self.cipher.as_ref();
+        let mut buf = Vec::new();
+        let mut markers_len = 0;
+        if batch {
+            buf.extend(seal(cipher, self.position, format.encode(&Record::Begin)?)?);
+            markers_len += buf.len();
+        }
+        let mut logs = Vec::with_capacity(records.len());
+        for record in records {
+            let offset = self.position + buf.len() as u64;
+            let encoded = seal(cipher, offset, format.encode(record)?)?;
+            let (value_len, stored_value_len) = record.value_lens();
+            logs.push(
+                LogInFile::new(self.current_gen, offset, encoded.len() as u64)
+                    .expiring(record.expires())
+                    .holding(value_len, stored_value_len),
+            );
+            buf.extend(encoded);
+        }
+        if batch {
+            let offset = self.position + buf.len() as u64;
+            let commit = seal(cipher, offset, format.encode(&Record::Commit)?)?;
+            markers_len += commit.len();
+            buf.extend(commit);
+        }
+        self.writer.write_all(&buf)?;
+        self.writer.flush()?;
+        self.position += buf.len() as u64;
+        // the markers are useless after compaction
+        self.uncompacted_size += markers_len as u64;
+        Ok(logs)
+    }
+
+    /// appends a set encoded by `binary_set_prefix` and the `value_len` bytes
+    /// of its value, and returns the log describing it
+    fn append_value(
+        &mut self,
+        prefix: &[u8],
+        value: impl Read,
+        value_len: u64,
+    ) -> Result<LogInFile> {
+        self.writer.write_all(prefix)?;
+        let copied = io::copy(&mut value.take(value_len), &mut self.writer)?;
+        if copied != value_len {
+            return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+        }
+        self.writer.flush()?;
+        let log = LogInFile::new(
+            self.current_gen,
+            self.position,
+            prefix.len() as u64 + value_len,
+        )
+        .holding(value_len, value_len);
+        self.position += log.length;
+        Ok(log)
+    }
+}
+
+/// the background compaction thread of a store, it stops when the last handle of the
+/// store is dropped
+struct Compactor {
+    sender: Option<SyncSender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// spawns the compaction thread, which compacts whenever it is woken up
+    /// and, if `compaction_interval` is set, on that schedule
+    fn spawn(shared: Arc<Shared>) -> Compactor {
+        // 容量为 1，已经有一次压缩在排队时不再重复唤醒
+        let (sender, receiver) = mpsc::sync_channel::<()>(1);
+        let interval = if shared.options.manual_compaction {
+            None
+        } else {
+            shared.options.compaction_interval
+        };
+        let handle = thread::spawn(move || loop {
+            let woken_up = match interval {
+                None => receiver.recv().is_ok(),
+                Some(interval) => match receiver.recv_timeout(interval) {
+                    Ok(()) => true,
+                    Err(RecvTimeoutError::Timeout) => {
+                        shared.writer.lock().unwrap().uncompacted_size > 0
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                },
+            };
+            if woken_up {
+                if let Err(e) = shared.compact() {
+                    *shared.compaction_error.lock().unwrap() = Some(e);
+                }
+            } else if interval.is_none() {
+                break;
+            }
+        });
+        Compactor {
+            sender: Some(sender),
+            handle: Some(handle),
+        }
+    }
+
+    fn wake_up(&self) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.try_send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // 关闭通道后线程做完手上的压缩就会退出
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// a reader of one segment, the format its records are encoded in
+/// and the cipher they are sealed with
+struct Segment {
+    reader: BufReader<File>,
+    format: LogFormat,
+    cipher: Option<FileCipher>,
+    /// where the first record starts
+    preamble_len: u64,
+}
+
+impl Segment {
+    fn open(path: &Path, gen: u64, options: &KvStoreOptions) -> Result<Segment> {
+        let mut reader = BufReader::new(File::open(log_path(path, gen))?);
+        let (format, cipher, preamble_len) = read_header(&mut reader, gen, options)?;
+        Ok(Segment {
+            reader,
+            format,
+            cipher,
+            preamble_len,
+        })
+    }
+
+    fn read_raw(&mut self, log: &LogInFile) -> Result<Vec<u8>> {
+        self.reader.seek(SeekFrom::Start(log.offset))?;
+        let mut record = Vec::with_capacity(log.length as usize);
+        (&mut self.reader)
+            .take(log.length)
+            .read_to_end(&mut record)?;
+        Ok(record)
+    }
+
+    /// the record as encoded in the format of the segment, opened if it is sealed
+    fn read_plain(&mut self, log: &LogInFile) -> Result<Vec<u8>> {
+        let record = self.read_raw(log)?;
+        match &self.cipher {
+            Some(cipher) => cipher.open(log.offset, &record),
+            None => Ok(record),
+        }
+    }
+
+    fn read(&mut self, log: &LogInFile) -> Result<Record> {
+        let record = self.read_plain(log)?;
+        self.format.decode(&record)?.decompress()
+    }
+}
+
+/// reads the beginning of a segment and returns the format of its records,
+/// the cipher they are sealed with and where the first of them starts
+///
+/// an encrypted segment starts with `ENCRYPTED_MAGIC`, the format and the header
+/// of its cipher, `KvsError::WrongKeyError` is returned if none of the keys opens it,
+/// a segment in plain text is only read by a store without a key or migrating to one
+fn read_header(
+    reader: &mut BufReader<File>,
+    gen: u64,
+    options: &KvStoreOptions,
+) -> Result<(LogFormat, Option<FileCipher>, u64)> {
+    let start = reader.fill_buf()?;
+    if start.is_empty()
+        || !(start.starts_with(ENCRYPTED_MAGIC) || ENCRYPTED_MAGIC.starts_with(start))
+    {
+        // 设了密钥以后，明文的段只能是被换掉的，空的段里没有记录
+        if !start.is_empty() && !options.reads_plaintext() {
+            return Err(KvsError::TamperedDataError);
+        }
+        let format = LogFormat::detect(reader)?;
+        return Ok((format, None, format.preamble().len() as u64));
+    }
+    let mut preamble = [0; ENCRYPTED_PREAMBLE_LEN];
+    match reader.read_exact(&mut preamble) {
+        Ok(()) => (),
+        // 加密头都没写完的段里没有记录，`load` 会把它清空
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => {
+            return Ok((LogFormat::Binary, None, preamble.len() as u64))
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let tag = preamble[ENCRYPTED_MAGIC.len()];
+    let format = LogFormat::from_tag(tag)?;
+    let header = &preamble[ENCRYPTED_MAGIC.len() + 1..];
+    let cipher = FileCipher::from_header(header, gen, tag, options.keys())?;
+    Ok((format, Some(cipher), preamble.len() as u64))
+}
+
+/// seals the encoded record written at the given offset if the segment is encrypted
+fn seal(cipher: Option<&FileCipher>, offset: u64, record: Vec<u8>) -> Result<Vec<u8>> {
+    match cipher {
+        Some(cipher) => cipher.seal(offset, &record),
+        None => Ok(record),
+    }
+}
+
+/// A read-only view of a `KvStore` as of the moment `KvsEngine::snapshot` was called.
+///
+/// Writes going on after that are not seen by it. The logs of the versions it sees
+/// are kept by compaction until the snapshot is dropped.
+pub struct KvStoreSnapshot {
+    store: KvStore,
+    seq: u64,
+}
+
+impl KvsSnapshot for KvStoreSnapshot {
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        self.store.get_at(&key.into(), self.seq)
+    }
+
+    /// Keys removed after the snapshot are found in the history of the store
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        let shared = &self.store.shared;
+        let keys = merge_keys(
+            shared
+                .map
+                .range(range.clone())
+                .map(|entry| entry.key().clone()),
+            shared.history.range(range).map(|entry| entry.key().clone()),
+        );
+        Ok(self.store.scan_at(keys, self.seq))
+    }
+}
+
+impl Drop for KvStoreSnapshot {
+    fn drop(&mut self) {
+        let mut writer = self.store.shared.writer.lock().unwrap();
+        let count = writer
+            .snapshots
+            .get_mut(&self.seq)
+            .expect("registered snapshot");
+        *count -= 1;
+        if *count == 0 {
+            writer.snapshots.remove(&self.seq);
+        }
+        self.store.shared.prune_history(&writer);
+    }
+}
+
+/// merges two ascending iterators of keys, a key found in both is returned once
+fn merge_keys(
+    a: impl Iterator<Item = Vec<u8>>,
+    b: impl Iterator<Item = Vec<u8>>,
+) -> impl Iterator<Item = Vec<u8>> {
+    let (mut a, mut b): (Peekable<_>, Peekable<_>) = (a.peekable(), b.peekable());
+    iter::from_fn(move || match (a.peek(), b.peek()) {
+        (Some(x), Some(y)) if x == y => {
+            b.next();
+            a.next()
+        }
+        (Some(x), Some(y)) if x > y => b.next(),
+        (Some(_), _) => a.next(),
+        (None, _) => b.next(),
+    })
+}
+
+/// a log in the index, with the sequence number of the write that stored it
+#[derive(Clone, Copy, PartialEq, Eq)]
+struct Version {
+    seq: u64,
+    log: LogInFile,
+}
+
+/// the sequence number and the log of a version kept for the snapshots,
+/// `None` marks a remove
+type OldVersion = (u64, Option<LogInFile>);
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct LogInFile {
+    pub(crate) gen: u64,
+    pub(crate) offset: u64,
+    pub(crate) length: u64,
+    /// when the value of the log expires, in milliseconds since the unix epoch
+    pub(crate) expires: Option<u64>,
+    /// the length of the value once decompressed
+    pub(crate) value_len: u64,
+    /// the length of the value as it is stored
+    pub(crate) stored_value_len: u64,
 }
 
 impl LogInFile {
-    fn new(offset: u64, length: u64) -> LogInFile {
-        LogInFile { offset, length }
+    pub(crate) fn new(gen: u64, offset: u64, length: u64) -> LogInFile {
+        LogInFile {
+            gen,
+            offset,
+            length,
+            expires: None,
+            value_len: 0,
+            stored_value_len: 0,
+        }
+    }
+
+    /// the same log, holding a value expiring at the given time
+    pub(crate) fn expiring(self, expires: Option<u64>) -> LogInFile {
+        LogInFile { expires, ..self }
+    }
+
+    /// the same log, holding a value of the given lengths, see `Record::value_lens`
+    pub(crate) fn holding(self, value_len: u64, stored_value_len: u64) -> LogInFile {
+        LogInFile {
+            value_len,
+            stored_value_len,
+            ..self
+        }
+    }
+
+    pub(crate) fn expired(&self, now: u64) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
     }
 }
 
-// In the author's code. The logs are in different files.
-// every time he open a kvstore, a new log file was build, and the next log file index
-// is found while scanning existing log file in the directory.
-// While opening, besides store the key-[log position] pair, he also store a key-reader pair
-// cause keys may not in the same log file.
+/// the current time in milliseconds since the unix epoch
+pub(crate) fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// when a value set now with the given time to live expires
+pub(crate) fn expiry(ttl: Duration) -> u64 {
+    unix_millis().saturating_add(ttl.as_millis().min(u64::MAX as u128) as u64)
+}
+
+fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+/// where a file is written before it is renamed to `path` in one step
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".tmp");
+    PathBuf::from(name)
+}
+
+/// returns the generation numbers of the `<gen>.<extension>` files in the directory,
+/// in ascending order
+fn sorted_gen_list(path: &Path, extension: &str) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = Vec::new();
+    for entry in fs::read_dir(path)? {
+        let path = entry?.path();
+        if path.is_file() && path.extension() == Some(OsStr::new(extension)) {
+            if let Some(gen) = path
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .and_then(|s| s.parse::<u64>().ok())
+            {
+                gen_list.push(gen);
+            }
+        }
+    }
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// creates the segment of the given generation at `file`, encrypted if a key is given,
+/// and returns the writer appending to it with the position of its first record
+/// and the cipher sealing its records
+fn new_log_file(
+    file: &Path,
+    gen: u64,
+    format: LogFormat,
+    key: Option<&EncryptionKey>,
+) -> Result<(BufWriter<File>, u64, Option<FileCipher>)> {
+    let f = OpenOptions::new().create(true).append(true).open(file)?;
+    let (preamble, cipher) = match key {
+        Some(key) => {
+            let (cipher, header) = FileCipher::create(key, gen, format.tag());
+            (format.encrypted_preamble(&header), Some(cipher))
+        }
+        None => (format.preamble().to_vec(), None),
+    };
+    let mut writer = BufWriter::new(f);
+    writer.write_all(&preamble)?;
+    writer.flush()?;
+    Ok((writer, preamble.len() as u64, cipher))
+}
+
+/// replays one segment into the map and returns how many of its bytes are stale
+/// and how many bytes were cut off its end
+///
+/// the newest segment is truncated right before a torn record or a last record that does
+/// not match its checksum, which is what a crash in the middle of a write leaves behind,
+/// a bad record followed by a complete one or in an older segment is reported as
+/// corruption instead,
+/// in an encrypted segment only a tail shorter than the smallest sealed record is torn,
+/// a longer record cut short or one failing authentication was tampered with,
+/// batch markers out of place and a batch left open in an older segment are corruption too
+fn load(
+    path: &Path,
+    gen: u64,
+    segment: &mut Segment,
+    newest: bool,
+    map: &mut HashMap<Vec<u8>, LogInFile>,
+) -> Result<(u64, u64)> {
+    let format = segment.format;
+    let file_len = segment.reader.get_ref().metadata()?.len();
+    let preamble_len = segment.preamble_len;
+    let mut uncompacted: u64 = 0;
+    let mut position = segment.reader.seek(SeekFrom::Start(preamble_len))?;
+    let mut record = Vec::new();
+    let mut batch: Option<PendingBatch> = None;
+    loop {
+        let read = match segment.cipher {
+            Some(_) => FileCipher::read_sealed(&mut segment.reader, &mut record),
+            None => format.read_record(&mut segment.reader, &mut record),
+        };
+        let len = match read {
+            Ok(0) => break,
+            Ok(len) => len,
+            Err(KvsError::IoError(ref e)) if e.kind() == ErrorKind::UnexpectedEof => {
+                if torn(segment, position, newest)? {
+                    break;
+                }
+                // 密文的长度是认证不了的，超出文件的长度只能当作被改过
+                return Err(match segment.cipher {
+                    Some(_) => KvsError::TamperedDataError,
+                    None => KvsError::CorruptedLogError,
+                });
+            }
+            Err(e) => return Err(e),
+        };
+        let opened;
+        let plain = match &segment.cipher {
+            None => &record,
+            Some(cipher) => {
+                opened = cipher.open(position, &record)?;
+                &opened
+            }
+        };
+        if format.is_padding(plain) {
+            position += len;
+            continue;
+        }
+        let decoded = match format.decode(plain) {
+            Ok(decoded) => decoded,
+            // 坏记录后面还跟着完整的记录，说明不是崩溃留下的尾巴，不能连带后面的一起截掉
+            Err(KvsError::CorruptedLogError) | Err(KvsError::SerdeError(_)) => {
+                if torn(segment, position, newest)? {
+                    break;
+                }
+                return Err(KvsError::CorruptedLogError);
+            }
+            Err(e) => return Err(e),
+        };
+        let (value_len, stored_value_len) = decoded.value_lens();
+        let log = LogInFile::new(gen, position, len)
+            .expiring(decoded.expires())
+            .holding(value_len, stored_value_len);
+        match (decoded, &mut batch) {
+            // batches are never nested
+            (Record::Begin, Some(_)) | (Record::Commit, None) => {
+                return Err(KvsError::CorruptedLogError)
+            }
+            (Record::Begin, None) => {
+                batch = Some(PendingBatch {
+                    begin: position,
+                    begin_len: len,
+                    records: Vec::new(),
+                })
+            }
+            (Record::Commit, Some(_)) => {
+                let pending = batch.take().unwrap();
+                for (record, log) in pending.records {
+                    uncompacted += replay(map, record, log);
+                }
+                uncompacted += pending.begin_len + len;
+            }
+            (record, Some(pending)) => pending.records.push((record, log)),
+            (record, None) => uncompacted += replay(map, record, log),
+        }
+        position += len;
+    }
+    // a batch without its commit at the end of the newest segment was cut short by a crash,
+    // it is dropped with the rest
+    if let Some(pending) = batch {
+        if !newest {
+            return Err(KvsError::CorruptedLogError);
+        }
+        position = pending.begin;
+    }
 
-// As same as the `compact` function as mine, he just scans the index-[log pos] map
-// and copy the exist log to a new file (Though the file name is 1+largest index of log file now).
+    // 预留头都没写完的段直接清空
+    let valid_len = if file_len < preamble_len { 0 } else { position };
+    if valid_len < file_len {
+        OpenOptions::new()
+            .write(true)
+            .open(log_path(path, gen))?
+            .set_len(valid_len)?;
+    }
+    Ok((uncompacted, file_len - valid_len))
+}
+
+/// whether the bad record at `position` is the tail of the newest segment,
+/// no complete record may start anywhere after its first byte,
+/// whatever its lengths say, and in an encrypted segment it must be too short to be sealed
+fn torn(segment: &mut Segment, position: u64, newest: bool) -> Result<bool> {
+    if !newest {
+        return Ok(false);
+    }
+    // 密文不打开就没法校验，只有放不下一条记录的尾巴才是崩溃留下的
+    if segment.cipher.is_some() {
+        let file_len = segment.reader.get_ref().metadata()?.len();
+        return Ok(file_len - position < MIN_SEALED_LEN);
+    }
+    segment.reader.seek(SeekFrom::Start(position + 1))?;
+    let mut rest = Vec::new();
+    segment.reader.read_to_end(&mut rest)?;
+    Ok(!segment.format.holds_record(&rest))
+}
+
+/// a batch read up to its begin marker, waiting for the commit marker
+struct PendingBatch {
+    begin: u64,
+    begin_len: u64,
+    records: Vec<(Record, LogInFile)>,
+}
+
+/// applies a replayed record to the map and returns how many bytes it made stale
+fn replay(map: &mut HashMap<Vec<u8>, LogInFile>, record: Record, log: LogInFile) -> u64 {
+    match record {
+        Record::Set { key, .. } => map.insert(key, log).map_or(0, |old| old.length),
+        Record::Rm { key } => map.remove(&key).map_or(0, |old| old.length) + log.length,
+        Record::Begin | Record::Commit => unreachable!("markers are not replayed"),
+    }
+}
+
+// Besides the key-[log position] pair, a reader is kept for every segment
+// cause keys may not in the same log file.
+// `compact` copies the live logs into the segment numbered right after the active one,
+// while new writes continue in a fresh segment after that, so only the segments older
+// than the compacted one are removed. Readers of removed segments are dropped lazily
+// once the safe point moves past them.
@@ -1,11 +1,36 @@
 // #![deny(missing_docs)]
 //! this crate is use to store key-value pair
-pub use engine::KvsEngine;
+pub use async_client::AsyncKvsClient;
+pub use batch::{BatchOp, WriteBatch};
+pub use client::{KvsClient, ValueStream};
+pub use compression::Compression;
+pub use crypto::EncryptionKey;
+pub use engine::{
+    AsyncKvsEngine, Blocking, BytesIter, KvsEngine, KvsFuture, KvsIter, KvsSnapshot, ValueReader,
+};
 pub use error::{KvsError, Result};
-pub use kv::{Command, KvStore};
-pub use sledstore::SledStore;
+pub use http::HttpGateway;
+pub use kv::{Command, KvStore, KvStoreSnapshot, KvStoreStats};
+pub use log_format::LogFormat;
+pub use options::KvStoreOptions;
+pub use protocol::{Request, Response};
+pub use sledstore::{SledSnapshot, SledStore};
+pub use thread_pool::ThreadPool;
 
+mod async_client;
+mod batch;
+mod bytes_serde;
+mod client;
+mod compression;
+mod crypto;
 mod engine;
 mod error;
+mod hint;
+pub mod http;
 mod kv;
+mod log_format;
+mod options;
+pub mod protocol;
+pub mod resp;
 mod sledstore;
+pub mod thread_pool;
@@ -0,0 +1,528 @@
+use crate::{BatchOp, Compression, KvsError, Result, ValueReader};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use crc32fast::Hasher;
+use serde::{Deserialize, Serialize};
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Cursor, ErrorKind, Read};
+
+/// magic bytes at the beginning of every binary segment,
+/// json segments start with a `{` so the two formats never collide
+const BINARY_MAGIC: &[u8] = b"KVSLOG\x00\x01";
+
+/// magic bytes at the beginning of an encrypted segment,
+/// followed by the format of its records as a byte and the header of its cipher
+pub(crate) const ENCRYPTED_MAGIC: &[u8] = b"KVSCRYPT";
+
+/// checksum(4) + key length(4) + value length(4) + type tag(1)
+const HEADER_LEN: usize = 13;
+
+const TAG_SET: u8 = 0;
+const TAG_RM: u8 = 1;
+const TAG_BEGIN: u8 = 2;
+const TAG_COMMIT: u8 = 3;
+const TAG_SET_TTL: u8 = 4;
+/// set on the tag of a set whose value is deflated
+const TAG_DEFLATE: u8 = 0x80;
+
+/// A record of the log.
+/// The records of a write batch are enclosed in `Begin` and `Commit`,
+/// a batch without its `Commit` at the end of the newest segment is dropped when the log
+/// is replayed.
+#[derive(Debug, Deserialize, Serialize)]
+pub(crate) enum Record {
+    Set {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes_serde")]
+        value: Vec<u8>,
+        /// when the value expires, in milliseconds since the unix epoch
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        expires: Option<u64>,
+        /// how `value` is compressed, in json segments a compressed value is base64 encoded
+        #[serde(default, skip_serializing_if = "Compression::is_none")]
+        compression: Compression,
+    },
+    Rm {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+    },
+    Begin,
+    Commit,
+}
+
+impl Record {
+    /// when the value set by the record expires, in milliseconds since the unix epoch
+    pub(crate) fn expires(&self) -> Option<u64> {
+        match self {
+            Record::Set { expires, .. } => *expires,
+            _ => None,
+        }
+    }
+
+    /// the length of the value of a set once decompressed and as it is stored,
+    /// both are 0 for other records
+    pub(crate) fn value_lens(&self) -> (u64, u64) {
+        match self {
+            Record::Set {
+                value, compression, ..
+            } => (
+                compression.decompressed_len(value).unwrap_or(0),
+                value.len() as u64,
+            ),
+            _ => (0, 0),
+        }
+    }
+
+    /// compresses the value of a set if it is at least `threshold` bytes long
+    /// and gets smaller, other records are returned as they are
+    pub(crate) fn compress(self, compression: Compression, threshold: usize) -> Record {
+        match self {
+            Record::Set {
+                key,
+                value,
+                expires,
+                compression: Compression::None,
+            } => match compression.compress(&value, threshold) {
+                Some(value) => Record::Set {
+                    key,
+                    value,
+                    expires,
+                    compression,
+                },
+                None => Record::Set {
+                    key,
+                    value,
+                    expires,
+                    compression: Compression::None,
+                },
+            },
+            record => record,
+        }
+    }
+
+    /// the record with the value of a set decompressed
+    pub(crate) fn decompress(self) -> Result<Record> {
+        match self {
+            Record::Set {
+                key,
+                value,
+                expires,
+                compression,
+            } if !compression.is_none() => Ok(Record::Set {
+                key,
+                value: compression.decompress(&value)?,
+                expires,
+                compression: Compression::None,
+            }),
+            record => Ok(record),
+        }
+    }
+}
+
+impl From<BatchOp> for Record {
+    fn from(op: BatchOp) -> Record {
+        match op {
+            BatchOp::Set { key, value } => Record::Set {
+                key,
+                value,
+                expires: None,
+                compression: Compression::None,
+            },
+            BatchOp::Rm { key } => Record::Rm { key },
+        }
+    }
+}
+
+/// The on-disk encoding of the records in a log segment.
+/// Every segment remembers its own format, so a store can hold both while migrating.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// one serde_json encoded record per line,
+    /// followed by a tab and the CRC32 checksum of the json in hex
+    ///
+    /// lines written before checksums were added carry no suffix and are taken as they are
+    Json,
+    /// length-prefixed binary records, each carrying a CRC32 checksum
+    ///
+    /// | checksum u32 | key length u32 | value length u32 | type tag u8 | key | value |
+    ///
+    /// integers are little endian, the checksum covers everything after itself,
+    /// a set with an expiry has its own tag and the expiry as a u64 in front of the value,
+    /// the high bit of the tag of a set marks a deflated value, see `Compression`
+    Binary,
+}
+
+impl LogFormat {
+    /// bytes written at the beginning of a new segment
+    pub(crate) fn preamble(self) -> &'static [u8] {
+        match self {
+            LogFormat::Json => b"",
+            LogFormat::Binary => BINARY_MAGIC,
+        }
+    }
+
+    /// bytes written at the beginning of a new encrypted segment,
+    /// the records after them are sealed by a cipher with the given header
+    pub(crate) fn encrypted_preamble(self, cipher_header: &[u8]) -> Vec<u8> {
+        let mut preamble = ENCRYPTED_MAGIC.to_vec();
+        preamble.push(self.tag());
+        preamble.extend_from_slice(cipher_header);
+        preamble
+    }
+
+    /// the byte standing for the format in `encrypted_preamble`
+    pub(crate) fn tag(self) -> u8 {
+        match self {
+            LogFormat::Json => 0,
+            LogFormat::Binary => 1,
+        }
+    }
+
+    /// the format written as a byte by `encrypted_preamble`
+    pub(crate) fn from_tag(tag: u8) -> Result<LogFormat> {
+        match tag {
+            0 => Ok(LogFormat::Json),
+            1 => Ok(LogFormat::Binary),
+            _ => Err(KvsError::CorruptedLogError),
+        }
+    }
+
+    /// finds out the format of a segment from its first bytes
+    pub(crate) fn detect(reader: &mut impl BufRead) -> Result<LogFormat> {
+        let mut magic = Vec::with_capacity(BINARY_MAGIC.len());
+        reader
+            .take(BINARY_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        // a shorter prefix of the magic is the preamble of a segment torn right after creation
+        if !magic.is_empty() && BINARY_MAGIC.starts_with(&magic) {
+            Ok(LogFormat::Binary)
+        } else if magic.is_empty() || b"{\"".contains(&magic[0]) || magic[0].is_ascii_whitespace() {
+            // `{` starts a set or a removal, `"` the marker of a batch
+            Ok(LogFormat::Json)
+        } else {
+            Err(KvsError::CorruptedLogError)
+        }
+    }
+
+    pub(crate) fn encode(self, record: &Record) -> Result<Vec<u8>> {
+        match self {
+            LogFormat::Json => {
+                let mut buf = match record {
+                    Record::Set {
+                        key,
+                        value,
+                        expires,
+                        compression,
+                    } if !compression.is_none() => serde_json::to_vec(&Record::Set {
+                        key: key.clone(),
+                        value: STANDARD.encode(value).into_bytes(),
+                        expires: *expires,
+                        compression: *compression,
+                    })?,
+                    record => serde_json::to_vec(record)?,
+                };
+                // serde_json escapes every control character, so a tab never shows up in the json
+                let checksum = crc32fast::hash(&buf);
+                buf.extend_from_slice(format!("\t{:08x}", checksum).as_bytes());
+                // Question: using `%` to separate commands can not pass the get_stored_key test
+                buf.push(b'\n');
+                Ok(buf)
+            }
+            LogFormat::Binary => {
+                let (tag, key, value, expires) = match record {
+                    Record::Set {
+                        key,
+                        value,
+                        expires,
+                        compression,
+                    } => {
+                        let tag = if expires.is_some() {
+                            TAG_SET_TTL
+                        } else {
+                            TAG_SET
+                        };
+                        let tag = match compression {
+                            Compression::None => tag,
+                            Compression::Deflate => tag | TAG_DEFLATE,
+                        };
+                        (tag, &key[..], &value[..], *expires)
+                    }
+                    Record::Rm { key } => (TAG_RM, &key[..], &[][..], None),
+                    Record::Begin => (TAG_BEGIN, &[][..], &[][..], None),
+                    Record::Commit => (TAG_COMMIT, &[][..], &[][..], None),
+                };
+                let expires = expires.map(u64::to_le_bytes);
+                let expires: &[u8] = expires.as_ref().map_or(&[], |bytes| &bytes[..]);
+                let value_len = expires.len() + value.len();
+                let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + value_len);
+                buf.extend_from_slice(&[0; 4]);
+                buf.extend_from_slice(&field_len(key.len() as u64)?.to_le_bytes());
+                buf.extend_from_slice(&field_len(value_len as u64)?.to_le_bytes());
+                buf.push(tag);
+                buf.extend_from_slice(key);
+                buf.extend_from_slice(expires);
+                buf.extend_from_slice(value);
+                let checksum = crc32fast::hash(&buf[4..]);
+                buf[..4].copy_from_slice(&checksum.to_le_bytes());
+                Ok(buf)
+            }
+        }
+    }
+
+    /// decodes one record produced by `read_record`,
+    /// the value of a set is left as it is stored, see `Record::decompress`
+    pub(crate) fn decode(self, record: &[u8]) -> Result<Record> {
+        let decoded = self.decode_stored(record)?;
+        match &decoded {
+            Record::Set {
+                value, compression, ..
+            } if compression.decompressed_len(value).is_none() => Err(KvsError::CorruptedLogError),
+            _ => Ok(decoded),
+        }
+    }
+
+    fn decode_stored(self, record: &[u8]) -> Result<Record> {
+        match self {
+            LogFormat::Json => {
+                let line = record.strip_suffix(b"\n").unwrap_or(record);
+                let json = match line.iter().rposition(|&b| b == b'\t') {
+                    None => line,
+                    Some(tab) => {
+                        let checksum = std::str::from_utf8(&line[tab + 1..])
+                            .ok()
+                            .and_then(|hex| u32::from_str_radix(hex, 16).ok());
+                        if checksum != Some(crc32fast::hash(&line[..tab])) {
+                            return Err(KvsError::CorruptedLogError);
+                        }
+                        &line[..tab]
+                    }
+                };
+                match serde_json::from_slice(json)? {
+                    Record::Set {
+                        key,
+                        value,
+                        expires,
+                        compression,
+                    } if !compression.is_none() => Ok(Record::Set {
+                        key,
+                        value: STANDARD
+                            .decode(&value)
+                            .map_err(|_| KvsError::CorruptedLogError)?,
+                        expires,
+                        compression,
+                    }),
+                    record => Ok(record),
+                }
+            }
+            LogFormat::Binary => {
+                if record.len() < HEADER_LEN {
+                    return Err(KvsError::CorruptedLogError);
+                }
+                let checksum = u32_at(record, 0);
+                let key_len = u32_at(record, 4) as usize;
+                let value_len = u32_at(record, 8) as usize;
+                if record.len() != HEADER_LEN + key_len + value_len
+                    || crc32fast::hash(&record[4..]) != checksum
+                {
+                    return Err(KvsError::CorruptedLogError);
+                }
+                let key = record[HEADER_LEN..HEADER_LEN + key_len].to_vec();
+                let value = &record[HEADER_LEN + key_len..];
+                let tag = record[HEADER_LEN - 1];
+                let compression = if tag & TAG_DEFLATE != 0 {
+                    Compression::Deflate
+                } else {
+                    Compression::None
+                };
+                match tag & !TAG_DEFLATE {
+                    TAG_SET => Ok(Record::Set {
+                        key,
+                        value: value.to_vec(),
+                        expires: None,
+                        compression,
+                    }),
+                    TAG_SET_TTL if value.len() >= 8 => Ok(Record::Set {
+                        key,
+                        value: value[8..].to_vec(),
+                        expires: Some(u64_at(value, 0)),
+                        compression,
+                    }),
+                    _ if compression == Compression::Deflate => Err(KvsError::CorruptedLogError),
+                    TAG_RM => Ok(Record::Rm { key }),
+                    TAG_BEGIN => Ok(Record::Begin),
+                    TAG_COMMIT => Ok(Record::Commit),
+                    _ => Err(KvsError::CorruptedLogError),
+                }
+            }
+        }
+    }
+
+    /// reads the raw bytes of the next record into `buf`
+    /// and returns how many bytes were consumed, `0` means the end of the segment
+    pub(crate) fn read_record(self, reader: &mut impl BufRead, buf: &mut Vec<u8>) -> Result<u64> {
+        buf.clear();
+        match self {
+            LogFormat::Json => Ok(reader.read_until(b'\n', buf)? as u64),
+            LogFormat::Binary => {
+                reader.take(HEADER_LEN as u64).read_to_end(buf)?;
+                if buf.is_empty() {
+                    return Ok(0);
+                }
+                if buf.len() < HEADER_LEN {
+                    return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+                }
+                let payload_len = u32_at(buf, 4) as u64 + u32_at(buf, 8) as u64;
+                if reader.take(payload_len).read_to_end(buf)? as u64 != payload_len {
+                    return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+                }
+                Ok(buf.len() as u64)
+            }
+        }
+    }
+
+    /// whether the raw record holds no command, like an empty line in a json segment
+    pub(crate) fn is_padding(self, record: &[u8]) -> bool {
+        self == LogFormat::Json && record.iter().all(u8::is_ascii_whitespace)
+    }
+
+    /// whether a complete record matching its checksum starts anywhere in `bytes`,
+    /// what is left after a record torn by a crash never holds one
+    pub(crate) fn holds_record(self, bytes: &[u8]) -> bool {
+        match self {
+            LogFormat::Json => bytes
+                .split(|&b| b == b'\n')
+                .skip(1)
+                .any(|line| !self.is_padding(line) && self.decode(line).is_ok()),
+            LogFormat::Binary => (0..bytes.len().saturating_sub(HEADER_LEN - 1)).any(|at| {
+                let rest = &bytes[at..];
+                let len = HEADER_LEN as u64 + u32_at(rest, 4) as u64 + u32_at(rest, 8) as u64;
+                len <= rest.len() as u64
+                    && crc32fast::hash(&rest[4..len as usize]) == u32_at(rest, 0)
+            }),
+        }
+    }
+}
+
+/// the binary encoding of a set up to its value, the value itself is written right after
+///
+/// `value` is the checksum of the value, so a large value can be encoded
+/// without holding it in memory
+pub(crate) fn binary_set_prefix(
+    key: &[u8],
+    value_len: u64,
+    value: &Hasher,
+    expires: Option<u64>,
+) -> Result<Vec<u8>> {
+    let expires = expires.map(u64::to_le_bytes);
+    let expires: &[u8] = expires.as_ref().map_or(&[], |bytes| &bytes[..]);
+    let key_len = field_len(key.len() as u64)?;
+    let value_len = field_len(value_len + expires.len() as u64)?;
+    let tag = if expires.is_empty() {
+        TAG_SET
+    } else {
+        TAG_SET_TTL
+    };
+    let mut buf = Vec::with_capacity(HEADER_LEN + key.len() + expires.len());
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&key_len.to_le_bytes());
+    buf.extend_from_slice(&value_len.to_le_bytes());
+    buf.push(tag);
+    buf.extend_from_slice(key);
+    buf.extend_from_slice(expires);
+    let mut checksum = Hasher::new();
+    checksum.update(&buf[4..]);
+    checksum.combine(value);
+    buf[..4].copy_from_slice(&checksum.finalize().to_le_bytes());
+    Ok(buf)
+}
+
+/// reads the value of the binary set record `reader` is positioned at,
+/// the checksum is verified once the value has been read to its end
+///
+/// a deflated value is read and inflated whole, it was small enough to be set in one piece
+pub(crate) fn binary_value_reader(mut reader: impl Read + Send + 'static) -> Result<ValueReader> {
+    let mut header = [0; HEADER_LEN];
+    reader.read_exact(&mut header)?;
+    if header[HEADER_LEN - 1] & TAG_DEFLATE != 0 {
+        let mut record = header.to_vec();
+        reader.read_to_end(&mut record)?;
+        return match LogFormat::Binary.decode(&record)?.decompress()? {
+            Record::Set { value, .. } => Ok(Box::new(Cursor::new(value))),
+            _ => Err(KvsError::CorruptedLogError),
+        };
+    }
+    let mut checksum = Hasher::new();
+    checksum.update(&header[4..]);
+    let mut skip = u32_at(&header, 4) as u64;
+    let mut value_len = u32_at(&header, 8) as u64;
+    match header[HEADER_LEN - 1] {
+        TAG_SET => (),
+        TAG_SET_TTL if value_len >= 8 => {
+            skip += 8;
+            value_len -= 8;
+        }
+        _ => return Err(KvsError::CorruptedLogError),
+    }
+    let mut skipped = Vec::with_capacity(skip as usize);
+    (&mut reader).take(skip).read_to_end(&mut skipped)?;
+    if skipped.len() as u64 != skip {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    checksum.update(&skipped);
+    Ok(Box::new(ChecksumReader {
+        reader: reader.take(value_len),
+        left: value_len,
+        checksum,
+        expected: u32_at(&header, 0),
+    }))
+}
+
+/// fails the read reaching the end of the value if the record does not match its checksum
+struct ChecksumReader<R> {
+    reader: io::Take<R>,
+    left: u64,
+    checksum: Hasher,
+    expected: u32,
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let len = self.reader.read(buf)?;
+        self.checksum.update(&buf[..len]);
+        self.left -= len as u64;
+        if len == 0 && self.left > 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        if len == 0 && self.checksum.clone().finalize() != self.expected {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                KvsError::CorruptedLogError.to_string(),
+            ));
+        }
+        Ok(len)
+    }
+}
+
+/// the length of a key or value as stored in a binary header
+fn field_len(len: u64) -> Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        KvsError::StringError("the key or the value is too large for the log".to_owned())
+    })
+}
+
+/// the little endian integers of the binary records, also used by hint files and the cipher
+pub(crate) fn u32_at(buf: &[u8], at: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buf[at..at + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+pub(crate) fn u64_at(buf: &[u8], at: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&buf[at..at + 8]);
+    u64::from_le_bytes(bytes)
+}
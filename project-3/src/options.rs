@@ -0,0 +1,167 @@
+use crate::{Compression, EncryptionKey, KvStore, KvsError, LogFormat, Result};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Options and flags which can be used to configure how a `KvStore` is opened,
+/// in the same fashion as `std::fs::OpenOptions`.
+///
+/// ```rust
+/// use kvs::{Compression, KvStoreOptions, LogFormat};
+/// use std::time::Duration;
+///
+/// # fn main() -> kvs::Result<()> {
+/// # let temp_dir = tempfile::TempDir::new().unwrap();
+/// let store = KvStoreOptions::new()
+///     .format(LogFormat::Binary)
+///     .compression(Compression::Deflate)
+///     .compaction_threshold(64 * 1024 * 1024)
+///     .compaction_interval(Duration::from_secs(60))
+///     .open(temp_dir.path())?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Automatic compaction runs on the background compaction thread of the store
+/// once any of the enabled triggers fires.
+#[derive(Debug, Clone)]
+pub struct KvStoreOptions {
+    pub(crate) format: LogFormat,
+    pub(crate) compaction_threshold: u64,
+    pub(crate) stale_ratio: Option<f64>,
+    pub(crate) manual_compaction: bool,
+    pub(crate) compaction_interval: Option<Duration>,
+    pub(crate) compression: Compression,
+    pub(crate) compression_threshold: usize,
+    pub(crate) encryption_key: Option<EncryptionKey>,
+    pub(crate) decryption_keys: Vec<EncryptionKey>,
+    pub(crate) migrate_plaintext: bool,
+}
+
+impl KvStoreOptions {
+    /// The default options write uncompressed `LogFormat::Json` records
+    /// and compact once 1 MiB of the log is stale.
+    pub fn new() -> KvStoreOptions {
+        KvStoreOptions {
+            format: LogFormat::Json,
+            compaction_threshold: 1024 * 1024,
+            stale_ratio: None,
+            manual_compaction: false,
+            compaction_interval: None,
+            compression: Compression::None,
+            compression_threshold: 256,
+            encryption_key: None,
+            decryption_keys: Vec::new(),
+            migrate_plaintext: false,
+        }
+    }
+
+    /// the format new records are written in, see `KvStore::open_with_format`
+    pub fn format(&mut self, format: LogFormat) -> &mut KvStoreOptions {
+        self.format = format;
+        self
+    }
+
+    /// compact once more than this many bytes of the log are stale
+    pub fn compaction_threshold(&mut self, bytes: u64) -> &mut KvStoreOptions {
+        self.compaction_threshold = bytes;
+        self
+    }
+
+    /// also compact once the stale bytes exceed `ratio` times the live bytes,
+    /// `open` fails unless the ratio is finite and above 0
+    pub fn stale_ratio(&mut self, ratio: f64) -> &mut KvStoreOptions {
+        self.stale_ratio = Some(ratio);
+        self
+    }
+
+    /// never compact automatically, only `KvStore::compact` compacts the log,
+    /// this also disables `compaction_interval`
+    pub fn manual_compaction(&mut self, manual: bool) -> &mut KvStoreOptions {
+        self.manual_compaction = manual;
+        self
+    }
+
+    /// also compact every `interval` if any part of the log is stale
+    pub fn compaction_interval(&mut self, interval: Duration) -> &mut KvStoreOptions {
+        self.compaction_interval = Some(interval);
+        self
+    }
+
+    /// how the values of new records are compressed,
+    /// every record is flagged with its compression so a log can mix them
+    pub fn compression(&mut self, compression: Compression) -> &mut KvStoreOptions {
+        self.compression = compression;
+        self
+    }
+
+    /// values shorter than this many bytes are stored raw, 256 by default
+    pub fn compression_threshold(&mut self, bytes: usize) -> &mut KvStoreOptions {
+        self.compression_threshold = bytes;
+        self
+    }
+
+    /// encrypts the segments and hint files written from now on with this key,
+    /// files encrypted with another key are re-encrypted by the next compaction,
+    /// which `open` starts right away unless compaction is manual
+    pub fn encryption_key(&mut self, key: EncryptionKey) -> &mut KvStoreOptions {
+        self.encryption_key = Some(key);
+        self
+    }
+
+    /// also reads the files encrypted with this key, to open a store whose key is being
+    /// rotated, it is no longer needed once the store has been compacted
+    pub fn decryption_key(&mut self, key: EncryptionKey) -> &mut KvStoreOptions {
+        self.decryption_keys.push(key);
+        self
+    }
+
+    /// also reads the segments and hint files in plain text while `encryption_key` is set,
+    /// to encrypt a store which was not encrypted, they are encrypted by the next compaction,
+    /// without it such files are taken as tampered with
+    pub fn migrate_plaintext(&mut self, migrate: bool) -> &mut KvStoreOptions {
+        self.migrate_plaintext = migrate;
+        self
+    }
+
+    /// whether files in plain text may be read
+    pub(crate) fn reads_plaintext(&self) -> bool {
+        self.encryption_key.is_none() || self.migrate_plaintext
+    }
+
+    /// the keys encrypted files may be read with
+    pub(crate) fn keys(&self) -> impl Iterator<Item = &EncryptionKey> {
+        self.encryption_key.iter().chain(&self.decryption_keys)
+    }
+
+    /// opens the store in the given directory with these options, see `KvStore::open`
+    pub fn open(&self, path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, self.clone())
+    }
+
+    /// checks the options which are not checked as they are set
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.stale_ratio {
+            Some(ratio) if !(ratio.is_finite() && ratio > 0.0) => Err(KvsError::StringError(
+                format!("invalid stale ratio {}, it must be above 0", ratio),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// whether the store should be compacted with that many stale and live bytes
+    pub(crate) fn should_compact(&self, uncompacted_size: u64, live_size: u64) -> bool {
+        if self.manual_compaction {
+            return false;
+        }
+        uncompacted_size > self.compaction_threshold
+            || self
+                .stale_ratio
+                .is_some_and(|ratio| uncompacted_size as f64 > ratio * live_size as f64)
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> KvStoreOptions {
+        KvStoreOptions::new()
+    }
+}
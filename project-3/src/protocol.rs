@@ -0,0 +1,296 @@
+//! the wire protocol spoken between `kvs-server` and `kvs-client`
+//!
+//! Every message is a frame: the length of its body as a big endian u32,
+//! followed by the body, a serde_json encoded `Request` from the client
+//! or a `Response` from the server.
+//!
+//! Keys and values are bytes, written as json strings when they are valid utf-8
+//! and as `{"base64": "..."}` otherwise, so the requests of text data are the
+//! same as the serialized `Command`s.
+//!
+//! A connection carries any number of requests. A client may send several
+//! requests before reading the responses, they are answered in order.
+//!
+//! Streamed values travel as chunks: frames whose body is a raw piece of the value,
+//! ended by an empty chunk. A `Request::SetStream` is followed by the chunks of the
+//! value and then answered; a `Request::GetStream` is answered by `Response::Stream`,
+//! the chunks, and a final `Response::Ok` or `Response::Error` once the value is read.
+
+use crate::{BatchOp, Command, KvsError, Result};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// frames larger than this are refused instead of allocating a buffer for them
+pub const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// the size of the chunks streamed values are cut into
+pub const CHUNK_LEN: usize = 64 * 1024;
+
+/// A request of the client, the binary-safe form of a `Command`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Request {
+    Set {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes_serde")]
+        value: Vec<u8>,
+        #[serde(default)]
+        ttl: Option<Duration>,
+    },
+    Rm {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+    },
+    Get {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+    },
+    Scan {
+        #[serde(with = "crate::bytes_serde::option")]
+        start: Option<Vec<u8>>,
+        #[serde(with = "crate::bytes_serde::option")]
+        end: Option<Vec<u8>>,
+        #[serde(with = "crate::bytes_serde::option")]
+        prefix: Option<Vec<u8>>,
+        limit: Option<usize>,
+    },
+    Cas {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes_serde::option")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "crate::bytes_serde::option")]
+        new: Option<Vec<u8>>,
+    },
+    Batch {
+        ops: Vec<BatchOp>,
+    },
+    /// gets the value in chunks
+    GetStream {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+    },
+    /// sets the value sent in the chunks following the request
+    SetStream {
+        #[serde(with = "crate::bytes_serde")]
+        key: Vec<u8>,
+    },
+}
+
+impl From<Command> for Request {
+    fn from(cmd: Command) -> Request {
+        match cmd {
+            Command::Set { key, value, ttl } => Request::Set {
+                key: key.into(),
+                value: value.into(),
+                ttl,
+            },
+            Command::Rm { key } => Request::Rm { key: key.into() },
+            Command::Get { key } => Request::Get { key: key.into() },
+            Command::Scan {
+                start,
+                end,
+                prefix,
+                limit,
+            } => Request::Scan {
+                start: start.map(String::into_bytes),
+                end: end.map(String::into_bytes),
+                prefix: prefix.map(String::into_bytes),
+                limit,
+            },
+            Command::Cas { key, expected, new } => Request::Cas {
+                key: key.into(),
+                expected: expected.map(String::into_bytes),
+                new: new.map(String::into_bytes),
+            },
+            Command::Batch { ops } => Request::Batch { ops },
+        }
+    }
+}
+
+/// The answer of the server to one `Request`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Response {
+    /// the request succeeded and has nothing to return
+    Ok,
+    /// the value of the key asked for
+    Value(#[serde(with = "crate::bytes_serde")] Vec<u8>),
+    /// the key does not exist
+    NotFound,
+    /// the pairs found by a `Request::Scan`, in ascending order of the keys
+    Pairs(#[serde(with = "crate::bytes_serde::pairs")] Vec<(Vec<u8>, Vec<u8>)>),
+    /// the value found by a `Request::Cas` instead of the expected one,
+    /// `None` if the key does not exist
+    Conflict(#[serde(with = "crate::bytes_serde::option")] Option<Vec<u8>>),
+    /// the value asked for by a `Request::GetStream` follows in chunks
+    Stream,
+    /// the request failed on the server
+    Error(String),
+}
+
+/// writes one frame holding `message`,
+/// the caller flushes the writer once it has nothing more to send
+pub fn write_frame<T: Serialize>(writer: &mut impl Write, message: &T) -> Result<()> {
+    writer.write_all(&encode_frame(message)?)?;
+    Ok(())
+}
+
+/// reads one frame, `None` means the peer closed the connection before a new frame
+pub fn read_frame<T: DeserializeOwned>(reader: &mut impl Read) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    // 连接在两个帧之间关闭是正常的结束
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let body = read_body(reader, len)?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// `write_frame` for tokio writers
+pub async fn write_frame_async<T: Serialize>(
+    writer: &mut (impl AsyncWrite + Unpin),
+    message: &T,
+) -> Result<()> {
+    writer.write_all(&encode_frame(message)?).await?;
+    Ok(())
+}
+
+/// `read_frame` for tokio readers
+pub async fn read_frame_async<T: DeserializeOwned>(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<T>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let body = read_body_async(reader, len).await?;
+    Ok(Some(serde_json::from_slice(&body)?))
+}
+
+/// writes one chunk of a streamed value, an empty one ends the value
+pub fn write_chunk(writer: &mut impl Write, chunk: &[u8]) -> Result<()> {
+    writer.write_all(&chunk_len(chunk)?)?;
+    writer.write_all(chunk)?;
+    Ok(())
+}
+
+/// reads one chunk of a streamed value, the connection must not end before the last one
+pub fn read_chunk(reader: &mut impl Read) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    read_body(reader, len)
+}
+
+/// `write_chunk` for tokio writers
+pub async fn write_chunk_async(writer: &mut (impl AsyncWrite + Unpin), chunk: &[u8]) -> Result<()> {
+    writer.write_all(&chunk_len(chunk)?).await?;
+    writer.write_all(chunk).await?;
+    Ok(())
+}
+
+/// `read_chunk` for tokio readers
+pub async fn read_chunk_async(reader: &mut (impl AsyncRead + Unpin)) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len).await?;
+    read_body_async(reader, len).await
+}
+
+/// Reads the chunks of a streamed value as one value, up to the empty chunk ending it
+pub struct ChunkReader<R> {
+    reader: R,
+    chunk: Cursor<Vec<u8>>,
+    done: bool,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R) -> ChunkReader<R> {
+        ChunkReader {
+            reader,
+            chunk: Cursor::new(Vec::new()),
+            done: false,
+        }
+    }
+
+    /// skips the rest of the value, so the next frame can be read
+    pub fn finish(&mut self) -> Result<()> {
+        while !self.done {
+            self.chunk = Cursor::new(read_chunk(&mut self.reader)?);
+            self.done = self.chunk.get_ref().is_empty();
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for ChunkReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let len = Read::read(&mut self.chunk, buf)?;
+            if len > 0 || buf.is_empty() || self.done {
+                return Ok(len);
+            }
+            self.chunk = Cursor::new(read_chunk(&mut self.reader)?);
+            self.done = self.chunk.get_ref().is_empty();
+        }
+    }
+}
+
+fn chunk_len(chunk: &[u8]) -> Result<[u8; 4]> {
+    if chunk.len() > MAX_FRAME_LEN as usize {
+        return Err(too_long(chunk.len()));
+    }
+    Ok((chunk.len() as u32).to_be_bytes())
+}
+
+fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>> {
+    let body = serde_json::to_vec(message)?;
+    if body.len() > MAX_FRAME_LEN as usize {
+        return Err(too_long(body.len()));
+    }
+    let mut frame = Vec::with_capacity(4 + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+fn body_len(len: [u8; 4]) -> Result<usize> {
+    let len = u32::from_be_bytes(len);
+    if len > MAX_FRAME_LEN {
+        return Err(too_long(len as usize));
+    }
+    Ok(len as usize)
+}
+
+/// reads the body announced by `len`, the buffer grows with the bytes received
+/// rather than with the length the peer claims
+fn read_body(reader: &mut impl Read, len: [u8; 4]) -> Result<Vec<u8>> {
+    let len = body_len(len)? as u64;
+    let mut body = Vec::new();
+    if reader.take(len).read_to_end(&mut body)? as u64 != len {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(body)
+}
+
+/// `read_body` for tokio readers
+async fn read_body_async(reader: &mut (impl AsyncRead + Unpin), len: [u8; 4]) -> Result<Vec<u8>> {
+    let len = body_len(len)? as u64;
+    let mut body = Vec::new();
+    if reader.take(len).read_to_end(&mut body).await? as u64 != len {
+        return Err(io::Error::from(ErrorKind::UnexpectedEof).into());
+    }
+    Ok(body)
+}
+
+fn too_long(len: usize) -> KvsError {
+    KvsError::StringError(format!(
+        "frame of {} bytes exceeds the limit of {} bytes",
+        len, MAX_FRAME_LEN
+    ))
+}
@@ -0,0 +1,368 @@
+//! a subset of the Redis serialization protocol (RESP),
+//! so `kvs-server` can be used by redis-cli and Redis client libraries
+//!
+//! The supported commands are `GET`, `SET` (with `EX` or `PX`), `DEL`, `EXISTS`, `PING`,
+//! `KEYS`, `DBSIZE` and `INFO`, plus `COMMAND` and `QUIT` which clients send on their own.
+
+use crate::protocol::MAX_FRAME_LEN;
+use crate::{KvsEngine, KvsError, Result};
+use std::io::{BufRead, Read, Write};
+use std::time::Duration;
+
+/// the longest line accepted, an inline command or the header of an array or a bulk string,
+/// as long as the inline commands of Redis
+const MAX_LINE_LEN: usize = 64 * 1024;
+
+/// the most arguments a command may have, same as Redis
+const MAX_ARGS: usize = 1024 * 1024;
+
+/// A RESP value, as sent back to the client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    /// `+OK`
+    Simple(String),
+    /// `-ERR message`
+    Error(String),
+    /// `:1`
+    Integer(i64),
+    /// `$5 hello`, `None` is the null bulk string
+    Bulk(Option<Vec<u8>>),
+    /// `*2 ...`
+    Array(Vec<Value>),
+}
+
+impl Value {
+    /// writes the value in its RESP encoding
+    pub fn write_to(&self, writer: &mut impl Write) -> Result<()> {
+        match self {
+            Value::Simple(s) => write!(writer, "+{}\r\n", s)?,
+            Value::Error(s) => write!(writer, "-{}\r\n", s)?,
+            Value::Integer(n) => write!(writer, ":{}\r\n", n)?,
+            Value::Bulk(None) => writer.write_all(b"$-1\r\n")?,
+            Value::Bulk(Some(bytes)) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Value::Array(values) => {
+                write!(writer, "*{}\r\n", values.len())?;
+                for value in values {
+                    value.write_to(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The result of running one command
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    /// send the value and wait for the next command
+    Value(Value),
+    /// send the value and close the connection
+    Close(Value),
+}
+
+/// reads the arguments of the next command, an array of bulk strings
+/// or an inline command like `PING` typed into a terminal,
+/// `None` means the client closed the connection
+pub fn read_command(reader: &mut impl BufRead) -> Result<Option<Vec<Vec<u8>>>> {
+    loop {
+        let line = match read_line(reader)? {
+            None => return Ok(None),
+            Some(line) => line,
+        };
+        if !line.starts_with(b"*") {
+            let args: Vec<Vec<u8>> = line
+                .split(|b| b.is_ascii_whitespace())
+                .filter(|arg| !arg.is_empty())
+                .map(|arg| arg.to_vec())
+                .collect();
+            // 空行被忽略, 和 redis 一样
+            if args.is_empty() {
+                continue;
+            }
+            return Ok(Some(args));
+        }
+
+        let count = parse_len(&line[1..])?;
+        if count > MAX_ARGS {
+            return Err(protocol_error("invalid multibulk length"));
+        }
+        let mut args = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let line = read_line(reader)?.ok_or_else(|| protocol_error("unexpected end"))?;
+            if !line.starts_with(b"$") {
+                return Err(protocol_error("expected '$'"));
+            }
+            let len = parse_len(&line[1..])?;
+            let mut arg = vec![0; len + 2];
+            reader.read_exact(&mut arg)?;
+            if !arg.ends_with(b"\r\n") {
+                return Err(protocol_error("bulk string without CRLF"));
+            }
+            arg.truncate(len);
+            args.push(arg);
+        }
+        if !args.is_empty() {
+            return Ok(Some(args));
+        }
+    }
+}
+
+/// runs one command against the store,
+/// failures of the store are sent to the client as errors
+pub fn execute(store: &impl KvsEngine, args: Vec<Vec<u8>>) -> Reply {
+    let command = match args.first() {
+        Some(command) => command,
+        None => return Reply::Value(Value::Error("ERR empty command".to_owned())),
+    };
+    let name = String::from_utf8_lossy(command).to_ascii_lowercase();
+    let value = match name.as_str() {
+        "quit" => return Reply::Close(Value::Simple("OK".to_owned())),
+        "ping" => ping(&args),
+        "get" => get(store, &args),
+        "set" => set(store, &args),
+        "del" => del(store, &args),
+        "exists" => exists(store, &args),
+        "keys" => keys(store, &args),
+        "dbsize" => dbsize(store, &args),
+        "info" => info(store, &args),
+        // redis-cli asks for the command table on start, an empty one is fine
+        "command" => Ok(Value::Array(Vec::new())),
+        _ => Ok(Value::Error(format!(
+            "ERR unknown command '{}'",
+            String::from_utf8_lossy(command)
+        ))),
+    };
+    Reply::Value(value.unwrap_or_else(|e| Value::Error(format!("ERR {}", e))))
+}
+
+fn ping(args: &[Vec<u8>]) -> Result<Value> {
+    match args.len() {
+        1 => Ok(Value::Simple("PONG".to_owned())),
+        2 => Ok(Value::Bulk(Some(args[1].clone()))),
+        _ => Ok(wrong_arity("ping")),
+    }
+}
+
+fn get(store: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Value> {
+    if args.len() != 2 {
+        return Ok(wrong_arity("get"));
+    }
+    Ok(Value::Bulk(store.get_bytes(&args[1][..])?))
+}
+
+fn set(store: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Value> {
+    match args.len() {
+        3 => store.set(&args[1][..], &args[2][..])?,
+        0..=2 => return Ok(wrong_arity("set")),
+        5 => match parse_ttl(&args[3], &args[4]) {
+            Ok(ttl) => store.set_with_ttl(&args[1][..], &args[2][..], ttl)?,
+            Err(error) => return Ok(error),
+        },
+        // NX, XX and the other options are not supported
+        _ => return Ok(Value::Error("ERR syntax error".to_owned())),
+    }
+    Ok(Value::Simple("OK".to_owned()))
+}
+
+/// the time to live of `SET ... EX seconds` or `SET ... PX milliseconds`,
+/// or the error sent back to the client
+fn parse_ttl(option: &[u8], amount: &[u8]) -> std::result::Result<Duration, Value> {
+    let unit = match &option.to_ascii_lowercase()[..] {
+        b"ex" => 1000,
+        b"px" => 1,
+        _ => return Err(Value::Error("ERR syntax error".to_owned())),
+    };
+    std::str::from_utf8(amount)
+        .ok()
+        .and_then(|amount| amount.parse::<u64>().ok())
+        .filter(|&amount| amount > 0)
+        .and_then(|amount| amount.checked_mul(unit))
+        .map(Duration::from_millis)
+        .ok_or_else(|| Value::Error("ERR invalid expire time in 'set' command".to_owned()))
+}
+
+fn del(store: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Value> {
+    if args.len() < 2 {
+        return Ok(wrong_arity("del"));
+    }
+    let mut removed = 0;
+    for key in &args[1..] {
+        match store.remove(&key[..]) {
+            Ok(()) => removed += 1,
+            Err(KvsError::KeyNotFoundError) => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(Value::Integer(removed))
+}
+
+fn exists(store: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Value> {
+    if args.len() < 2 {
+        return Ok(wrong_arity("exists"));
+    }
+    let mut found = 0;
+    for key in &args[1..] {
+        if store.get_bytes(&key[..])?.is_some() {
+            found += 1;
+        }
+    }
+    Ok(Value::Integer(found))
+}
+
+fn keys(store: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Value> {
+    if args.len() != 2 {
+        return Ok(wrong_arity("keys"));
+    }
+    Ok(Value::Array(
+        store
+            .keys_bytes()?
+            .into_iter()
+            .filter(|key| glob_match(&args[1], key))
+            .map(|key| Value::Bulk(Some(key)))
+            .collect(),
+    ))
+}
+
+fn dbsize(store: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Value> {
+    if args.len() != 1 {
+        return Ok(wrong_arity("dbsize"));
+    }
+    Ok(Value::Integer(store.keys_bytes()?.len() as i64))
+}
+
+fn info(store: &impl KvsEngine, args: &[Vec<u8>]) -> Result<Value> {
+    if args.len() > 2 {
+        return Ok(Value::Error("ERR syntax error".to_owned()));
+    }
+    let info = format!(
+        "# Server\r\nkvs_version:{}\r\n\r\n# Keyspace\r\ndb0:keys={}\r\n",
+        env!("CARGO_PKG_VERSION"),
+        store.keys_bytes()?.len()
+    );
+    Ok(Value::Bulk(Some(info.into_bytes())))
+}
+
+/// matches `text` against a Redis glob pattern with `*`, `?`, `[...]` and `\`
+///
+/// on a mismatch only the last `*` is retried one byte further,
+/// the earlier stars never need to take back what they matched
+pub fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // the pattern right after the last star and where in the text it was tried
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if pattern.get(p) == Some(&b'*') {
+            p += 1;
+            star = Some((p, t));
+        } else if let Some(next) = match_one(pattern, p, text[t]) {
+            p = next;
+            t += 1;
+        } else if let Some((after_star, tried)) = star {
+            p = after_star;
+            t = tried + 1;
+            star = Some((after_star, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&b| b == b'*')
+}
+
+/// where the pattern goes on if its element at `p` matches the byte `c`
+fn match_one(pattern: &[u8], p: usize, c: u8) -> Option<usize> {
+    let next = match pattern.get(p)? {
+        b'?' => p + 1,
+        b'[' => match pattern[p + 1..].iter().position(|&b| b == b']') {
+            Some(end) => {
+                let (negate, set) = match &pattern[p + 1..p + 1 + end] {
+                    [b'^', set @ ..] => (true, set),
+                    set => (false, set),
+                };
+                if class_match(set, c) == negate {
+                    return None;
+                }
+                p + end + 2
+            }
+            // an unclosed `[` is taken literally
+            None if c == b'[' => p + 1,
+            None => return None,
+        },
+        b'\\' if p + 1 < pattern.len() => {
+            if pattern[p + 1] != c {
+                return None;
+            }
+            p + 2
+        }
+        &literal => {
+            if literal != c {
+                return None;
+            }
+            p + 1
+        }
+    };
+    Some(next)
+}
+
+fn class_match(set: &[u8], c: u8) -> bool {
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == b'-' {
+            let (low, high) = (set[i].min(set[i + 2]), set[i].max(set[i + 2]));
+            if low <= c && c <= high {
+                return true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                return true;
+            }
+            i += 1;
+        }
+    }
+    false
+}
+
+fn read_line(reader: &mut impl BufRead) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader
+        .by_ref()
+        .take(MAX_LINE_LEN as u64 + 1)
+        .read_until(b'\n', &mut line)?
+        == 0
+    {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        if line.len() >= MAX_LINE_LEN {
+            return Err(protocol_error("too big request"));
+        }
+        return Err(protocol_error("unexpected end"));
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|&len| len <= MAX_FRAME_LEN as usize)
+        .ok_or_else(|| protocol_error("invalid length"))
+}
+
+fn wrong_arity(command: &str) -> Value {
+    Value::Error(format!(
+        "ERR wrong number of arguments for '{}' command",
+        command
+    ))
+}
+
+fn protocol_error(message: &str) -> KvsError {
+    KvsError::StringError(format!("Protocol error: {}", message))
+}
@@ -1,40 +1,330 @@
-use crate::KvsEngine;
+use crate::kv::{expiry, unix_millis};
+use crate::{BatchOp, BytesIter, KvsEngine, KvsSnapshot, WriteBatch};
 use crate::{KvsError, Result};
+use sled::transaction::{
+    ConflictableTransactionError, ConflictableTransactionResult, TransactionError,
+    TransactionalTree, UnabortableTransactionError,
+};
+use sled::Transactional;
+use std::collections::BTreeMap;
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
 
+/// a `KvsEngine` backed by sled, clones share the same `sled::Db`
+#[derive(Clone)]
 pub struct SledStore {
     sled: sled::Db,
+    /// the expiry of the keys set with a ttl, in milliseconds since the unix epoch
+    ttl: sled::Tree,
+    /// the values replaced while a snapshot copies the tree, under the id of the snapshot
+    /// followed by the key
+    undo: sled::Tree,
+    /// the ids of the snapshots copying the tree,
+    /// writes share the lock so none of them is missed by a snapshot starting
+    copying: Arc<RwLock<Vec<u64>>>,
 }
 
 impl SledStore {
     pub fn open(path: impl Into<PathBuf>) -> Result<SledStore> {
         let mut path: PathBuf = path.into();
         path.push("sled-data");
+        let sled = sled::open(path)?;
+        let undo = sled.open_tree("undo")?;
+        // 上次退出时没复制完的快照
+        undo.clear()?;
         Ok(SledStore {
-            sled: sled::open(path)?,
+            ttl: sled.open_tree("ttl")?,
+            undo,
+            sled,
+            copying: Arc::new(RwLock::new(Vec::new())),
         })
     }
-}
 
-impl KvsEngine for SledStore {
-    fn set(&mut self, key: String, value: String) -> Result<()> {
-        self.sled.insert(key.as_bytes(), value.as_bytes())?;
+    /// runs `f` in a transaction over the data and the expiries, then flushes,
+    /// the first value `keys` had since a snapshot started copying is kept for it
+    fn transaction<T>(
+        &self,
+        keys: &[&[u8]],
+        f: impl Fn(&TransactionalTree, &TransactionalTree) -> ConflictableTransactionResult<T, KvsError>,
+    ) -> Result<T> {
+        let copying = self.copying.read().unwrap();
+        let result = (&*self.sled, &self.ttl, &self.undo).transaction(|(data, ttl, undo)| {
+            for id in copying.iter() {
+                for key in keys {
+                    let mut slot = id.to_be_bytes().to_vec();
+                    slot.extend_from_slice(key);
+                    if undo.get(&slot)?.is_none() {
+                        // 第一个字节标明当时有没有这个键
+                        let value = match current(data, ttl, key)? {
+                            Some(value) => [&[1], &value[..]].concat(),
+                            None => vec![0],
+                        };
+                        undo.insert(slot, value)?;
+                    }
+                }
+            }
+            f(data, ttl)
+        });
+        let value = match result {
+            Ok(value) => value,
+            Err(TransactionError::Abort(e)) => return Err(e),
+            Err(TransactionError::Storage(e)) => return Err(e.into()),
+        };
+        drop(copying);
         self.sled.flush()?;
+        Ok(value)
+    }
+
+    /// takes the values a snapshot kept out of the `undo` tree and puts them into its copy
+    fn restore(&self, id: u64, pairs: &mut BTreeMap<Vec<u8>, Vec<u8>>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for item in self.undo.scan_prefix(id.to_be_bytes()) {
+            let (slot, value) = item?;
+            let key = slot[8..].to_vec();
+            match value.split_first() {
+                Some((1, value)) => pairs.insert(key, value.to_vec()),
+                _ => pairs.remove(&key),
+            };
+            batch.remove(slot);
+        }
+        self.undo.apply_batch(batch)?;
         Ok(())
     }
 
-    fn get(&mut self, key: String) -> Result<Option<String>> {
-        let t = self.sled.get(key.as_bytes())?;
-        Ok(t.map(|v| String::from_utf8(v.to_vec()).expect("Found invalid utf-8")))
+    fn expired(&self, key: &[u8]) -> Result<bool> {
+        Ok(self.ttl.get(key)?.is_some_and(|expires| is_past(&expires)))
+    }
+
+    /// the pair unless it has expired
+    fn live(
+        &self,
+        item: sled::Result<(sled::IVec, sled::IVec)>,
+    ) -> Option<Result<(Vec<u8>, Vec<u8>)>> {
+        let expired = match &item {
+            Ok((key, _)) => self.expired(key),
+            Err(_) => Ok(false),
+        };
+        match expired {
+            Ok(true) => None,
+            Ok(false) => Some(
+                item.map(|(key, value)| (key.to_vec(), value.to_vec()))
+                    .map_err(KvsError::from),
+            ),
+            Err(e) => Some(Err(e)),
+        }
+    }
+}
+
+impl KvsEngine for SledStore {
+    fn set(&self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        self.transaction(&[&key], |data, ttl| {
+            data.insert(&key[..], &value[..])?;
+            ttl.remove(&key[..])?;
+            Ok(())
+        })
     }
 
-    fn remove(&mut self, key: String) -> Result<()> {
-        let v = self.sled.remove(key.as_bytes())?;
-        if v == None {
-            Err(KvsError::KeyNotFoundError)
-        } else {
-            self.sled.flush()?;
+    /// The expiry is kept in the `ttl` tree next to the data
+    fn set_with_ttl(
+        &self,
+        key: impl Into<Vec<u8>>,
+        value: impl Into<Vec<u8>>,
+        ttl: Duration,
+    ) -> Result<()> {
+        let (key, value) = (key.into(), value.into());
+        let expires = expiry(ttl).to_be_bytes();
+        self.transaction(&[&key], |data, ttl| {
+            data.insert(&key[..], &value[..])?;
+            ttl.insert(&key[..], &expires)?;
             Ok(())
+        })
+    }
+
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        let key = key.into();
+        let t = self.sled.get(&key)?;
+        if t.is_some() && self.expired(&key)? {
+            return Ok(None);
+        }
+        Ok(t.map(|v| v.to_vec()))
+    }
+
+    fn remove(&self, key: impl Into<Vec<u8>>) -> Result<()> {
+        let key = key.into();
+        self.transaction(&[&key], |data, ttl| remove(data, ttl, &key))
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(Box::new(
+            self.sled
+                .range(range)
+                .filter_map(move |item| self.live(item)),
+        ))
+    }
+
+    fn scan_prefix_bytes(&self, prefix: impl Into<Vec<u8>>) -> Result<BytesIter<'_>> {
+        Ok(Box::new(
+            self.sled
+                .scan_prefix(prefix.into())
+                .filter_map(move |item| self.live(item)),
+        ))
+    }
+
+    fn keys_bytes(&self) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for key in self.sled.iter().keys() {
+            let key = key?;
+            if !self.expired(&key)? {
+                keys.push(key.to_vec());
+            }
+        }
+        Ok(keys)
+    }
+
+    fn scan_prefix_keys(&self, prefix: impl Into<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+        let mut keys = Vec::new();
+        for key in self.sled.scan_prefix(prefix.into()).keys() {
+            let key = key?;
+            if !self.expired(&key)? {
+                keys.push(key.to_vec());
+            }
         }
+        Ok(keys)
+    }
+
+    /// The batch runs in a sled transaction
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let keys: Vec<&[u8]> = batch
+            .ops()
+            .iter()
+            .map(|op| match op {
+                BatchOp::Set { key, .. } | BatchOp::Rm { key } => &key[..],
+            })
+            .collect();
+        self.transaction(&keys, |data, ttl| {
+            for op in batch.ops() {
+                match op {
+                    BatchOp::Set { key, value } => {
+                        data.insert(&key[..], &value[..])?;
+                        ttl.remove(&key[..])?;
+                    }
+                    BatchOp::Rm { key } => remove(data, ttl, key)?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// The comparison and the swap run in a sled transaction,
+    /// so an expired value counts as a missing key
+    fn compare_and_swap_bytes(
+        &self,
+        key: impl Into<Vec<u8>>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<std::result::Result<(), Option<Vec<u8>>>> {
+        let key = key.into();
+        self.transaction(&[&key], |data, ttl| {
+            let current = current(data, ttl, &key)?.map(|v| v.to_vec());
+            if current != expected {
+                return Ok(Err(current));
+            }
+            match &new {
+                Some(value) => {
+                    data.insert(&key[..], &value[..])?;
+                }
+                None => {
+                    data.remove(&key[..])?;
+                }
+            }
+            ttl.remove(&key[..])?;
+            Ok(Ok(()))
+        })
     }
+
+    type Snapshot = SledSnapshot;
+
+    /// sled has no snapshots, the whole tree is copied, which takes time and memory
+    /// in the size of the store,
+    /// writes go on during the copy and keep the values they replace,
+    /// which are put back into the copy once it is done
+    fn snapshot(&self) -> Result<SledSnapshot> {
+        let id = self.sled.generate_id()?;
+        self.copying.write().unwrap().push(id);
+        let mut pairs = BTreeMap::new();
+        let copied: Result<()> = self
+            .sled
+            .iter()
+            .filter_map(|item| self.live(item))
+            .try_for_each(|pair| {
+                let (key, value) = pair?;
+                pairs.insert(key, value);
+                Ok(())
+            });
+        self.copying
+            .write()
+            .unwrap()
+            .retain(|copying| *copying != id);
+        // 复制失败也要清掉留下的旧值
+        self.restore(id, &mut pairs)?;
+        copied?;
+        Ok(SledSnapshot { pairs })
+    }
+}
+
+/// A copy of a `SledStore` taken by `KvsEngine::snapshot`
+pub struct SledSnapshot {
+    pairs: BTreeMap<Vec<u8>, Vec<u8>>,
+}
+
+impl KvsSnapshot for SledSnapshot {
+    fn get_bytes(&self, key: impl Into<Vec<u8>>) -> Result<Option<Vec<u8>>> {
+        Ok(self.pairs.get(&key.into()).cloned())
+    }
+
+    fn scan_bytes<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<BytesIter<'_>> {
+        Ok(Box::new(
+            self.pairs
+                .range(range)
+                .map(|(key, value)| Ok((key.clone(), value.clone()))),
+        ))
+    }
+}
+
+/// removes the key in a transaction, aborting it if the key does not exist or has expired
+fn remove(
+    data: &TransactionalTree,
+    ttl: &TransactionalTree,
+    key: &[u8],
+) -> ConflictableTransactionResult<(), KvsError> {
+    let expired = ttl.remove(key)?.is_some_and(|expires| is_past(&expires));
+    if data.remove(key)?.is_none() || expired {
+        return Err(ConflictableTransactionError::Abort(
+            KvsError::KeyNotFoundError,
+        ));
+    }
+    Ok(())
+}
+
+/// the value of the key in a transaction, unless it has expired
+fn current(
+    data: &TransactionalTree,
+    ttl: &TransactionalTree,
+    key: &[u8],
+) -> std::result::Result<Option<sled::IVec>, UnabortableTransactionError> {
+    match ttl.get(key)? {
+        Some(expires) if is_past(&expires) => Ok(None),
+        _ => data.get(key),
+    }
+}
+
+/// whether the expiry stored in the `ttl` tree has passed
+fn is_past(expires: &[u8]) -> bool {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&expires[..8]);
+    u64::from_be_bytes(bytes) <= unix_millis()
 }
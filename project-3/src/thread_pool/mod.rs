@@ -0,0 +1,22 @@
+use crate::Result;
+
+pub use self::naive::NaiveThreadPool;
+pub use self::rayon::RayonThreadPool;
+pub use self::shared_queue::SharedQueueThreadPool;
+
+mod naive;
+mod rayon;
+mod shared_queue;
+
+/// A pool of threads running the jobs handed to it
+pub trait ThreadPool {
+    /// creates a pool with the given number of threads
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// runs the job on one of the threads of the pool
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
@@ -0,0 +1,25 @@
+use super::ThreadPool;
+use crate::Result;
+
+/// A pool backed by a `rayon::ThreadPool`
+pub struct RayonThreadPool {
+    pool: rayon::ThreadPool,
+}
+
+impl ThreadPool for RayonThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads as usize)
+            // without a handler rayon aborts the process when a spawned job panics
+            .panic_handler(|_| ())
+            .build()?;
+        Ok(RayonThreadPool { pool })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.pool.spawn(job);
+    }
+}
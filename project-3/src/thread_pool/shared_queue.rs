@@ -0,0 +1,135 @@
+use super::ThreadPool;
+use crate::Result;
+use std::collections::VecDeque;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread;
+
+/// A fixed number of worker threads taking jobs from one shared queue,
+/// the pool from building-blocks/thread-pool
+pub struct SharedQueueThreadPool {
+    workers: VecDeque<Worker>,
+    sender: mpsc::Sender<Message>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let mut workers = VecDeque::with_capacity(threads as usize);
+        let (tx, rx) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(rx));
+        for _ in 0..threads {
+            workers.push_back(Worker::new(Arc::clone(&receiver)));
+        }
+        Ok(SharedQueueThreadPool {
+            workers,
+            sender: tx,
+        })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Message::NewJob(Box::new(job)))
+            .expect("The thread pool has no thread");
+    }
+}
+
+impl Drop for SharedQueueThreadPool {
+    fn drop(&mut self) {
+        for _ in &self.workers {
+            let _ = self.sender.send(Message::Terminate);
+        }
+        for worker in &mut self.workers {
+            // a dying thread puts its replacement into the slot before it exits,
+            // so the slot is drained until it stays empty
+            while let Some(thread) = worker.take_thread() {
+                let _ = thread.join();
+            }
+        }
+    }
+}
+
+type Receiver = Arc<Mutex<mpsc::Receiver<Message>>>;
+
+type Slot = Arc<Mutex<Option<thread::JoinHandle<()>>>>;
+
+struct Worker {
+    thread: Slot,
+}
+
+impl Worker {
+    fn new(receiver: Receiver) -> Worker {
+        let thread = Arc::new(Mutex::new(None));
+        spawn_in(receiver, Arc::clone(&thread));
+        Worker { thread }
+    }
+
+    fn take_thread(&self) -> Option<thread::JoinHandle<()>> {
+        self.thread
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take()
+    }
+}
+
+/// starts a worker thread and puts its handle into the slot
+fn spawn_in(receiver: Receiver, slot: Slot) {
+    let mut guard = slot.lock().unwrap_or_else(PoisonError::into_inner);
+    let sentinel = Sentinel {
+        receiver,
+        slot: Arc::clone(&slot),
+    };
+    *guard = Some(thread::spawn(move || sentinel.run()));
+}
+
+/// Owned by a worker thread, it starts a new thread in its place
+/// when the worker unwinds, so the pool keeps its number of threads.
+struct Sentinel {
+    receiver: Receiver,
+    slot: Slot,
+}
+
+impl Sentinel {
+    fn run(&self) {
+        loop {
+            // a thread that panicked while holding the lock poisons it,
+            // but the queue behind it is still fine
+            let message = self
+                .receiver
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .recv();
+            let message = match message {
+                Ok(message) => message,
+                // the pool is gone
+                Err(_) => break,
+            };
+
+            match message {
+                // a panicking job must not take the worker down with it
+                Message::NewJob(job) => {
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                }
+                Message::Terminate => break,
+            }
+        }
+    }
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            spawn_in(Arc::clone(&self.receiver), Arc::clone(&self.slot));
+        }
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+enum Message {
+    NewJob(Job),
+    Terminate,
+}
@@ -0,0 +1,317 @@
+#![allow(clippy::zombie_processes)]
+
+use assert_cmd::prelude::*;
+use kvs::protocol::{read_frame, write_frame, Request, Response};
+use kvs::{
+    AsyncKvsClient, AsyncKvsEngine, Blocking, KvStore, KvsClient, KvsEngine, KvsError, Result,
+    WriteBatch,
+};
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+use tempfile::TempDir;
+
+// A client should run many requests over one connection
+#[test]
+fn client_access_server() -> Result<()> {
+    let addr = "127.0.0.1:4008";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key1".to_owned())?, None);
+    client.set("key1".to_owned(), "value1".to_owned())?;
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+    client.set("key1".to_owned(), "Key not found".to_owned())?;
+    assert_eq!(
+        client.get("key1".to_owned())?,
+        Some("Key not found".to_owned())
+    );
+    client.remove("key1".to_owned())?;
+    match client.remove("key1".to_owned()) {
+        Err(KvsError::KeyNotFoundError) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert_eq!(client.get("key1".to_owned())?, None);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server to exit");
+    Ok(())
+}
+
+// A frame that is not a request should be answered with an error on an open connection
+#[test]
+fn malformed_request() -> Result<()> {
+    for &(mode, addr) in &[("sync", "127.0.0.1:4023"), ("async", "127.0.0.1:4024")] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--addr", addr, "--mode", mode])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let mut stream = TcpStream::connect(addr)?;
+        stream.write_all(&[0, 0, 0, 7])?;
+        stream.write_all(b"garbage")?;
+        write_frame(
+            &mut stream,
+            &Request::Get {
+                key: b"key1".to_vec(),
+            },
+        )?;
+        match read_frame::<Response>(&mut stream)? {
+            Some(Response::Error(_)) => (),
+            other => panic!("malformed request answered with {:?}", other),
+        }
+        assert_eq!(
+            read_frame::<Response>(&mut stream)?,
+            Some(Response::NotFound)
+        );
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for the server to exit");
+    }
+    Ok(())
+}
+
+// A server that never answers should make the request time out
+#[test]
+fn client_timeout() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let addr = listener.local_addr()?;
+    let handle = thread::spawn(move || listener.accept());
+
+    let mut client = KvsClient::connect_timeout(addr, Some(Duration::from_millis(200)))?;
+    let start = Instant::now();
+    assert!(client.get("key1".to_owned()).is_err());
+    assert!(start.elapsed() < Duration::from_secs(5));
+
+    drop(handle.join().unwrap()?);
+    Ok(())
+}
+
+// The async server should speak the same protocol to both clients
+#[tokio::test(flavor = "multi_thread")]
+async fn async_client_access_async_server() -> Result<()> {
+    let addr = "127.0.0.1:4009";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--mode", "async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let mut clients = Vec::new();
+    for _ in 0..100 {
+        clients.push(AsyncKvsClient::connect(addr).await?);
+    }
+    let mut tasks = Vec::new();
+    for (i, mut client) in clients.into_iter().enumerate() {
+        tasks.push(tokio::spawn(async move {
+            client
+                .set(format!("key{}", i), format!("value{}", i))
+                .await?;
+            client.get(format!("key{}", i)).await
+        }));
+    }
+    for (i, task) in tasks.into_iter().enumerate() {
+        assert_eq!(task.await.unwrap()?, Some(format!("value{}", i)));
+    }
+
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.remove("key0".to_owned()).await?;
+    match client.remove("key0".to_owned()).await {
+        Err(KvsError::KeyNotFoundError) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key0".to_owned(), "batched".to_owned())
+        .remove("key2".to_owned());
+    client.write_batch(batch.clone()).await?;
+    match client.write_batch(batch).await {
+        Err(KvsError::KeyNotFoundError) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let mut client = KvsClient::connect(addr)?;
+    assert_eq!(client.get("key0".to_owned())?, Some("batched".to_owned()));
+    assert_eq!(client.get("key2".to_owned())?, None);
+    assert_eq!(client.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server to exit");
+    Ok(())
+}
+
+// Every engine should work through the async interface,
+// with the methods of both traits called on their own types
+#[tokio::test]
+async fn async_engine() -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = Blocking::new(KvStore::open(temp_dir.path())?);
+
+    store.set("key1".to_owned(), "value1".to_owned()).await?;
+    assert_eq!(
+        store.get_bytes("key1".to_owned()).await?,
+        Some(b"value1".to_vec())
+    );
+    assert_eq!(
+        store.engine().get("key1".to_owned())?,
+        Some("value1".to_owned())
+    );
+    store.remove("key1".to_owned()).await?;
+    assert_eq!(store.get_bytes("key1".to_owned()).await?, None);
+    store.engine().set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(
+        store.get_bytes("key2".to_owned()).await?,
+        Some(b"value2".to_vec())
+    );
+    Ok(())
+}
+
+// the bytes of a value spanning many chunks
+fn large_value() -> Vec<u8> {
+    (0..5 * 1024 * 1024 + 7).map(|i| (i % 251) as u8).collect()
+}
+
+// Large values should be streamed both ways, the connection staying usable
+#[test]
+fn stream_values() -> Result<()> {
+    let addr = "127.0.0.1:4016";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    // 丢弃的连接可能还没释放，留出第二个连接的位置
+    let mut child = server
+        .args(["--addr", addr, "--threads", "2"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let value = large_value();
+    let mut client = KvsClient::connect(addr)?;
+    client.set_reader("large".to_owned(), &value[..])?;
+    let mut read = Vec::new();
+    client
+        .get_reader("large".to_owned())?
+        .expect("the value was set")
+        .read_to_end(&mut read)?;
+    assert!(read == value);
+    assert!(client.get_reader("missing".to_owned())?.is_none());
+    assert_eq!(client.get_bytes("large".to_owned())?, Some(value.clone()));
+
+    // a reader dropped halfway leaves the next request a fresh connection
+    let mut half = vec![0; value.len() / 2];
+    client
+        .get_reader("large".to_owned())?
+        .expect("the value was set")
+        .read_exact(&mut half)?;
+    client.set_reader("small".to_owned(), &b"value"[..])?;
+    assert_eq!(client.get("small".to_owned())?, Some("value".to_owned()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server to exit");
+    Ok(())
+}
+
+// the most memory the process has held at once
+#[cfg(target_os = "linux")]
+fn peak_memory(pid: u32) -> u64 {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).unwrap();
+    let kilobytes = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|line| line.split_whitespace().next())
+        .expect("no VmHWM in the status of the server");
+    kilobytes.parse::<u64>().unwrap() * 1024
+}
+
+// Values much larger than a frame should stream through the server without being held
+// in its memory, a json store should refuse them instead of reading them whole
+#[cfg(target_os = "linux")]
+#[test]
+fn stream_bounded_memory() -> Result<()> {
+    const MIB: u64 = 1024 * 1024;
+    for &(format, addr, len, limit) in &[
+        ("binary", "127.0.0.1:4020", 256 * MIB, 64 * MIB),
+        ("json", "127.0.0.1:4021", 512 * MIB, 256 * MIB),
+    ] {
+        let temp_dir = TempDir::new().unwrap();
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(["--addr", addr, "--format", format])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        let mut client = KvsClient::connect(addr)?;
+        let result = client.set_reader("large".to_owned(), io::repeat(7).take(len));
+        if format == "binary" {
+            result?;
+            let reader = client.get_reader("large".to_owned())?;
+            let copied = io::copy(&mut reader.expect("the value was set"), &mut io::sink())?;
+            assert_eq!(copied, len);
+        } else {
+            assert!(result.is_err());
+            assert_eq!(client.get_bytes("large".to_owned())?, None);
+        }
+        let peak = peak_memory(child.id());
+        assert!(peak < limit, "{} server peaked at {} bytes", format, peak);
+
+        child.kill().expect("server exited before killed");
+        child.wait().expect("failed to wait for the server to exit");
+    }
+    Ok(())
+}
+
+// The async server and client should stream values too
+#[tokio::test(flavor = "multi_thread")]
+async fn stream_values_async() -> Result<()> {
+    let addr = "127.0.0.1:4017";
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--addr", addr, "--mode", "async"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let value = large_value();
+    let mut client = AsyncKvsClient::connect(addr).await?;
+    client.set_reader("large".to_owned(), &value[..]).await?;
+    let mut read = Vec::new();
+    assert!(client.get_into("large".to_owned(), &mut read).await?);
+    assert!(read == value);
+    assert!(!client.get_into("missing".to_owned(), &mut read).await?);
+    client.set("small".to_owned(), "value".to_owned()).await?;
+
+    // the sync client streams through the async server as well
+    let mut client = KvsClient::connect(addr)?;
+    let mut read = Vec::new();
+    client
+        .get_reader("large".to_owned())?
+        .expect("the value was set")
+        .read_to_end(&mut read)?;
+    assert!(read == value);
+    client.set_reader("large".to_owned(), &b"replaced"[..])?;
+    assert_eq!(client.get("large".to_owned())?, Some("replaced".to_owned()));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server to exit");
+    Ok(())
+}
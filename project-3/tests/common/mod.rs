@@ -0,0 +1,80 @@
+// helpers shared by the integration tests
+#![allow(dead_code)]
+
+use kvs::{KvStore, KvsEngine, KvsError, LogFormat, Result, SledStore};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+/// An engine the tests written once against `KvsEngine` run on,
+/// along with the few `KvStore` methods they check
+pub trait TestEngine: KvsEngine {
+    /// sled has a single format and ignores it
+    fn open_with_format(dir: &Path, format: LogFormat) -> Result<Self>;
+
+    /// bytes cut off the log when it was opened, always 0 for sled
+    fn discarded_bytes(&self) -> u64;
+
+    /// compacts the log, nothing to do for sled
+    fn compact(&self) -> Result<()>;
+}
+
+impl TestEngine for KvStore {
+    fn open_with_format(dir: &Path, format: LogFormat) -> Result<Self> {
+        KvStore::open_with_format(dir, format)
+    }
+
+    fn discarded_bytes(&self) -> u64 {
+        KvStore::discarded_bytes(self)
+    }
+
+    fn compact(&self) -> Result<()> {
+        KvStore::compact(self)
+    }
+}
+
+impl TestEngine for SledStore {
+    fn open_with_format(dir: &Path, _format: LogFormat) -> Result<Self> {
+        // sled lets go of the lock on its directory a little after the store is dropped
+        let mut attempts = 0;
+        loop {
+            match SledStore::open(dir) {
+                Err(KvsError::SledError(_)) if attempts < 50 => {
+                    attempts += 1;
+                    thread::sleep(Duration::from_millis(100));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn discarded_bytes(&self) -> u64 {
+        0
+    }
+
+    fn compact(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// declares a `kvs_engine` and a `sled_engine` test for each generic test function
+/// `fn name<E: TestEngine>(format: LogFormat) -> Result<()>`,
+/// the `KvStore` one runs with both log formats
+macro_rules! engine_tests {
+    ($($test:ident),* $(,)?) => {$(
+        mod $test {
+            use kvs::{KvStore, LogFormat, Result, SledStore};
+
+            #[test]
+            fn kvs_engine() -> Result<()> {
+                super::$test::<KvStore>(LogFormat::Json)?;
+                super::$test::<KvStore>(LogFormat::Binary)
+            }
+
+            #[test]
+            fn sled_engine() -> Result<()> {
+                super::$test::<SledStore>(LogFormat::Binary)
+            }
+        }
+    )*};
+}
@@ -0,0 +1,72 @@
+use kvs::{EncryptionKey, KvStoreOptions, KvsEngine, LogFormat, Result};
+use tempfile::TempDir;
+
+fn key_bytes() -> [u8; 32] {
+    let mut key = [0; 32];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = 0x80 + i as u8;
+    }
+    key
+}
+
+fn encrypted(key: EncryptionKey) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new();
+    options
+        .format(LogFormat::Binary)
+        .manual_compaction(true)
+        .encryption_key(key);
+    options
+}
+
+// Should read key files holding raw bytes or hex digits
+#[test]
+fn key_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let raw = temp_dir.path().join("raw.key");
+    std::fs::write(&raw, (0x80..0xa0).collect::<Vec<u8>>())?;
+    let hex = temp_dir.path().join("hex.key");
+    std::fs::write(
+        &hex,
+        "808182838485868788898a8b8c8d8e8f909192939495969798999a9b9c9d9e9f\n",
+    )?;
+
+    for path in [raw, hex].iter() {
+        let store_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = encrypted(EncryptionKey::from_file(path)?).open(store_dir.path())?;
+        store.set("key".to_owned(), "value".to_owned())?;
+        drop(store);
+        let store = encrypted(EncryptionKey::new(key_bytes())).open(store_dir.path())?;
+        assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+    }
+
+    let bad = temp_dir.path().join("bad.key");
+    std::fs::write(&bad, "not a key")?;
+    assert!(EncryptionKey::from_file(&bad).is_err());
+    Ok(())
+}
+
+// Should never print the bytes of the key
+#[test]
+fn key_debug() {
+    let printed = format!("{:?}", EncryptionKey::new(key_bytes()));
+    assert!(printed.starts_with("EncryptionKey("));
+    assert!(!printed.contains("808182"));
+    assert_ne!(printed, format!("{:?}", EncryptionKey::new([0; 32])));
+}
+
+// Values spanning many blocks of the key stream should be read back whole
+#[test]
+fn large_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let value: String = (0..100_000)
+        .map(|i| (b'a' + (i % 26) as u8) as char)
+        .collect();
+    let store = encrypted(EncryptionKey::new(key_bytes())).open(temp_dir.path())?;
+    store.set("key".to_owned(), value.clone())?;
+    store.compact()?;
+    drop(store);
+
+    let store = encrypted(EncryptionKey::new(key_bytes())).open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some(value));
+    Ok(())
+}
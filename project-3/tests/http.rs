@@ -0,0 +1,115 @@
+#[macro_use]
+mod common;
+
+use common::TestEngine;
+use kvs::protocol::MAX_FRAME_LEN;
+use kvs::thread_pool::SharedQueueThreadPool;
+use kvs::{HttpGateway, LogFormat, Result, ThreadPool};
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread;
+use tempfile::TempDir;
+
+// sends one request and returns the status code and the body of the response
+fn request(addr: SocketAddr, method: &str, path: &str, body: &str) -> (u16, String) {
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "{} {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Length: {}\r\n\r\n{}",
+        method,
+        path,
+        body.len(),
+        body
+    )
+    .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    let status = response[9..12].parse().unwrap();
+    let body = match response.find("\r\n\r\n") {
+        Some(at) => response[at + 4..].to_owned(),
+        None => String::new(),
+    };
+    (status, body)
+}
+
+fn http_gateway<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open_with_format(temp_dir.path(), format)?;
+    let gateway = HttpGateway::bind(store, "127.0.0.1:0")?;
+    let addr = gateway.local_addr().unwrap();
+    thread::spawn(move || gateway.run(SharedQueueThreadPool::new(4).unwrap()));
+
+    assert_eq!(request(addr, "GET", "/keys/key1", "").0, 404);
+    assert_eq!(
+        request(addr, "PUT", "/keys/key1", "value1"),
+        (204, String::new())
+    );
+    assert_eq!(request(addr, "PUT", "/keys/key%2F2", "value 2").0, 204);
+    assert_eq!(request(addr, "PUT", "/keys/other", "value3").0, 204);
+    assert_eq!(
+        request(addr, "GET", "/keys/key1", ""),
+        (200, r#"{"key":"key1","value":"value1"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys/key%2F2", ""),
+        (200, r#"{"key":"key/2","value":"value 2"}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys?prefix=key", ""),
+        (200, r#"{"keys":["key/2","key1"]}"#.to_owned())
+    );
+    assert_eq!(
+        request(addr, "GET", "/keys", ""),
+        (200, r#"{"keys":["key/2","key1","other"]}"#.to_owned())
+    );
+
+    assert_eq!(request(addr, "DELETE", "/keys/key1", "").0, 204);
+    let (status, body) = request(addr, "DELETE", "/keys/key1", "");
+    assert_eq!(status, 404);
+    assert!(body.contains("Key not found"));
+    assert_eq!(request(addr, "GET", "/keys/key1", "").0, 404);
+
+    assert_eq!(request(addr, "POST", "/keys/key1", "value1").0, 405);
+    assert_eq!(request(addr, "GET", "/values", "").0, 404);
+    assert_eq!(request(addr, "GET", "/keys/%zz", "").0, 400);
+    Ok(())
+}
+
+engine_tests!(http_gateway);
+
+// A chunked body over the limit should be refused, not stored cut short
+fn http_chunked_too_large<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().unwrap();
+    let store = E::open_with_format(temp_dir.path(), format)?;
+    let gateway = HttpGateway::bind(store.clone(), "127.0.0.1:0")?;
+    let addr = gateway.local_addr().unwrap();
+    thread::spawn(move || gateway.run(SharedQueueThreadPool::new(1).unwrap()));
+
+    let mut stream = TcpStream::connect(addr).unwrap();
+    write!(
+        stream,
+        "PUT /keys/big HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\
+         Transfer-Encoding: chunked\r\n\r\n"
+    )
+    .unwrap();
+    let chunk = vec![b'x'; 1024 * 1024];
+    let mut sent = 0;
+    // the server may stop reading and answer before the whole body is sent
+    while sent <= MAX_FRAME_LEN as usize {
+        if write!(stream, "{:x}\r\n", chunk.len()).is_err()
+            || stream.write_all(&chunk).is_err()
+            || stream.write_all(b"\r\n").is_err()
+        {
+            break;
+        }
+        sent += chunk.len();
+    }
+    let _ = stream.write_all(b"0\r\n\r\n");
+    let mut response = String::new();
+    stream.read_to_string(&mut response).unwrap();
+    assert_eq!(&response[9..12], "413");
+    assert_eq!(store.get_bytes("big")?, None);
+    Ok(())
+}
+
+engine_tests!(http_chunked_too_large);
@@ -1,4 +1,15 @@
-use kvs::{KvStore, KvsEngine, Result};
+#[macro_use]
+mod common;
+
+use common::TestEngine;
+use kvs::{
+    Compression, EncryptionKey, KvStore, KvStoreOptions, KvsEngine, KvsError, KvsSnapshot,
+    LogFormat, Result, WriteBatch,
+};
+use std::io::Read;
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +17,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +27,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +38,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +47,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +59,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +75,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +83,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +95,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +125,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +135,1451 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// Should take over the single `kvs-data.json` log written by older versions
+#[test]
+fn open_legacy_log() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    std::fs::write(
+        temp_dir.path().join("kvs-data.json"),
+        "{\"Set\":{\"key\":\"key1\",\"value\":\"value1\"}}\n\
+         {\"Set\":{\"key\":\"key2\",\"value\":\"value2\"}}\n\
+         {\"Rm\":{\"key\":\"key1\"}}\n",
+    )?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert!(!temp_dir.path().join("kvs-data.json").exists());
+
+    Ok(())
+}
+
+// Should store values containing newlines with the binary format
+#[test]
+fn binary_format() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Binary)?;
+
+    store.set("key1".to_owned(), "line1\nline2".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("line1\nline2".to_owned())
+    );
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Binary)?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("line1\nline2".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Should rewrite a json log in the binary format during compaction
+#[test]
+fn migrate_json_to_binary() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Binary)?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    for entry in std::fs::read_dir(temp_dir.path())? {
+        let path = entry?.path();
+        if path.extension() == Some("log".as_ref()) {
+            assert!(std::fs::read(path)?.starts_with(b"KVSLOG"));
+        }
+    }
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Binary)?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// Should cut a torn or corrupted tail off the log instead of failing to open
+#[test]
+fn recover_torn_tail() -> Result<()> {
+    for &format in &[LogFormat::Json, LogFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_format(temp_dir.path(), format)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        drop(store);
+
+        // cut the last record in half and append some garbage to the segment
+        let log = temp_dir.path().join("1.log");
+        let len = std::fs::metadata(&log)?.len();
+        let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+        file.set_len(len - 5)?;
+        drop(file);
+        let mut file = std::fs::OpenOptions::new().append(true).open(&log)?;
+        std::io::Write::write_all(&mut file, b"garbage")?;
+        drop(file);
+
+        let store = KvStore::open_with_format(temp_dir.path(), format)?;
+        let discarded = store.discarded_bytes();
+        assert!(discarded > 0);
+        assert_eq!(std::fs::metadata(&log)?.len(), len + 2 - discarded);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        store.set("key2".to_owned(), "value3".to_owned())?;
+
+        // Open from disk again and check persistent data
+        drop(store);
+        let store = KvStore::open_with_format(temp_dir.path(), format)?;
+        assert_eq!(store.discarded_bytes(), 0);
+        assert_eq!(store.get("key2".to_owned())?, Some("value3".to_owned()));
+    }
+    Ok(())
+}
+
+// Should refuse to open a log damaged in the middle instead of cutting off the records after it
+#[test]
+fn corrupted_mid_segment() -> Result<()> {
+    for &format in &[LogFormat::Json, LogFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_format(temp_dir.path(), format)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let log = temp_dir.path().join("1.log");
+        let mut content = std::fs::read(&log)?;
+        let at = content
+            .windows(6)
+            .position(|window| window == b"value2")
+            .expect("value2 not found in the log");
+        content[at + 5] = b'X';
+        std::fs::write(&log, &content)?;
+
+        match KvStore::open_with_format(temp_dir.path(), format) {
+            Err(KvsError::CorruptedLogError) => (),
+            other => panic!("corrupted log opened: {:?}", other.map(|_| ())),
+        }
+        assert_eq!(std::fs::read(&log)?, content);
+    }
+    Ok(())
+}
+
+// Should refuse a record whose length was changed instead of taking it for a torn tail
+#[test]
+fn corrupted_record_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Binary)?;
+    for i in 1..=4 {
+        store.set(format!("key{}", i), format!("value{}", i))?;
+    }
+    drop(store);
+
+    // the value length of the first record, right after the 8 bytes of the magic,
+    // the checksum and the key length, now runs past the end of the only segment
+    let log = temp_dir.path().join("1.log");
+    let mut content = std::fs::read(&log)?;
+    content[8 + 8 + 3] = 0x40;
+    std::fs::write(&log, &content)?;
+    match KvStore::open_with_format(temp_dir.path(), LogFormat::Binary) {
+        Err(KvsError::CorruptedLogError) => (),
+        other => panic!("corrupted log opened: {:?}", other.map(|_| ())),
+    }
+    assert_eq!(std::fs::read(&log)?, content);
+
+    // a torn tail is only left in the newest segment
+    content[8 + 8 + 3] = 0;
+    content.truncate(content.len() - 2);
+    std::fs::write(&log, &content)?;
+    std::fs::write(temp_dir.path().join("2.log"), b"KVSLOG\x00\x01")?;
+    match KvStore::open_with_format(temp_dir.path(), LogFormat::Binary) {
+        Err(KvsError::CorruptedLogError) => (),
+        other => panic!("torn older segment opened: {:?}", other.map(|_| ())),
+    }
+    assert_eq!(std::fs::read(&log)?, content);
+    Ok(())
+}
+
+// Should rebuild the index from the hint file written by compaction,
+// and fall back to replaying the segments when the hint file is damaged
+#[test]
+fn hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.compact()?;
+    store.set("key1".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    drop(store);
+
+    let hint = temp_dir.path().join("2.hint");
+    assert!(hint.is_file());
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    let original = std::fs::read(&hint)?;
+    let mut content = original.clone();
+    let last = content.len() - 1;
+    content[last] ^= 0xff;
+    std::fs::write(&hint, content)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!hint.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    drop(store);
+
+    // a few bytes too short for another entry, under a checksum that matches
+    let mut content = original[..original.len() - 4].to_vec();
+    content.extend_from_slice(&[1, 0]);
+    let checksum = crc32fast::hash(&content);
+    content.extend_from_slice(&checksum.to_le_bytes());
+    std::fs::write(&hint, content)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(!hint.exists());
+    assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// Whether a compaction has left its hint file in the directory
+fn compacted(dir: &Path) -> bool {
+    std::fs::read_dir(dir)
+        .expect("unable to read the store directory")
+        .any(|entry| entry.unwrap().path().extension() == Some("hint".as_ref()))
+}
+
+// Wait up to the given time for the background thread to finish a compaction
+fn wait_for_compaction(dir: &Path, millis: u64) -> bool {
+    for _ in 0..millis / 100 {
+        if compacted(dir) {
+            return true;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    false
+}
+
+// Should only compact when asked to in manual mode
+#[test]
+fn manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(0)
+        .manual_compaction(true)
+        .open(temp_dir.path())?;
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(!wait_for_compaction(temp_dir.path(), 500));
+
+    store.compact()?;
+    assert!(wait_for_compaction(temp_dir.path(), 5000));
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+    Ok(())
+}
+
+// Should compact once the stale data outgrows the live data by the given ratio
+#[test]
+fn stale_ratio_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .stale_ratio(4.0)
+        .open(temp_dir.path())?;
+    for iter in 0..4 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+    }
+    assert!(!wait_for_compaction(temp_dir.path(), 500));
+
+    store.set("key1".to_owned(), "value".to_owned())?;
+    store.set("key1".to_owned(), "value".to_owned())?;
+    assert!(wait_for_compaction(temp_dir.path(), 5000));
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+// Should refuse a ratio that would compact on every write or never
+#[test]
+fn invalid_stale_ratio() {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    for &ratio in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+        match KvStoreOptions::new()
+            .stale_ratio(ratio)
+            .open(temp_dir.path())
+        {
+            Err(KvsError::StringError(_)) => (),
+            other => panic!("opened with ratio {}: {:?}", ratio, other.map(|_| ())),
+        }
+    }
+}
+
+// Should compact on the given schedule
+#[test]
+fn scheduled_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(u64::MAX)
+        .compaction_interval(Duration::from_millis(200))
+        .open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key1".to_owned(), "value2".to_owned())?;
+    assert!(wait_for_compaction(temp_dir.path(), 5000));
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+    Ok(())
+}
+
+// Reads from other threads should see every value while the background thread compacts
+#[test]
+fn get_during_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .manual_compaction(true)
+        .open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..200 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            let dir = temp_dir.path().to_owned();
+            thread::spawn(move || loop {
+                let done = compacted(&dir);
+                for key_id in 0..200 {
+                    let value = store.get(format!("key{}", key_id)).unwrap();
+                    assert_eq!(value, Some(format!("value{}-9", key_id)));
+                }
+                if done {
+                    break;
+                }
+            })
+        })
+        .collect();
+    store.compact()?;
+    assert!(wait_for_compaction(temp_dir.path(), 5000));
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    Ok(())
+}
+
+// Should open the segments and hint file left by compaction along with later writes
+#[test]
+fn reopen_after_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut store = KvStoreOptions::new()
+        .manual_compaction(true)
+        .open(temp_dir.path())?;
+    for iter in 0..10 {
+        for key_id in 0..50 {
+            store.set(
+                format!("key{}", key_id),
+                format!("value{}-{}", key_id, iter),
+            )?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+    store.compact()?;
+    assert!(wait_for_compaction(temp_dir.path(), 5000));
+    store.set("key1".to_owned(), "later".to_owned())?;
+    store.remove("key2".to_owned())?;
+
+    // Open from disk again and check persistent data, twice to replay what the first open wrote
+    for _ in 0..2 {
+        drop(store);
+        store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("later".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+        for key_id in 3..50 {
+            let value = store.get(format!("key{}", key_id))?;
+            assert_eq!(value, Some(format!("value{}-9", key_id)));
+        }
+    }
+    Ok(())
+}
+
+// Should share one store between the clones handed to several threads
+#[test]
+fn concurrent_set_get() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for key_id in 0..100 {
+                    let key = format!("key{}-{}", thread_id, key_id);
+                    store.set(key.clone(), format!("value{}", key_id)).unwrap();
+                    assert_eq!(store.get(key).unwrap(), Some(format!("value{}", key_id)));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    // Open from disk again and check persistent data
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..100 {
+            let key = format!("key{}-{}", thread_id, key_id);
+            assert_eq!(store.get(key)?, Some(format!("value{}", key_id)));
+        }
+    }
+    Ok(())
+}
+
+// Should keep serving reads from other threads while writes and compactions go on
+#[test]
+fn concurrent_get_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .compaction_threshold(4 * 1024)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for iter in 0..20 {
+                    for key_id in 0..100 {
+                        let value = store.get(format!("key{}", key_id)).unwrap();
+                        let value: u32 = value.expect("key lost").parse().unwrap();
+                        assert!(value < 50, "iteration {}", iter);
+                    }
+                }
+            })
+        })
+        .collect();
+    for iter in 0..50 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), format!("{}", iter))?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap();
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("49".to_owned()));
+    }
+    Ok(())
+}
+
+// Should list every live key in order
+#[test]
+fn list_keys() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert!(store.keys()?.is_empty());
+
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.remove("key2".to_owned())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["key1".to_owned(), "key3".to_owned()]);
+    Ok(())
+}
+
+fn scan<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    for key in &["b", "a", "ab", "abc", "c", "d"] {
+        engine.set(key.to_string(), format!("value-{}", key))?;
+    }
+    engine.set("ab".to_owned(), "new".to_owned())?;
+    engine.remove("c".to_owned())?;
+
+    let keys = |pairs: Vec<(String, String)>| -> Vec<String> {
+        pairs.into_iter().map(|(key, _)| key).collect()
+    };
+    let all = engine.scan(..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(all.clone()), vec!["a", "ab", "abc", "b", "d"]);
+    assert_eq!(all[1], ("ab".to_owned(), "new".to_owned()));
+
+    let range = engine
+        .scan("ab".to_owned().."d".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(range), vec!["ab", "abc", "b"]);
+    let range = engine
+        .scan("ab".to_owned()..="d".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(range), vec!["ab", "abc", "b", "d"]);
+
+    let prefix = engine
+        .scan_prefix("ab".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(keys(prefix), vec!["ab", "abc"]);
+    assert_eq!(engine.scan_prefix("x".to_owned())?.count(), 0);
+    assert_eq!(
+        engine.scan_prefix_keys("ab")?,
+        vec![b"ab".to_vec(), b"abc".to_vec()]
+    );
+    assert!(engine.scan_prefix_keys("x")?.is_empty());
+    Ok(())
+}
+
+// Should iterate over ranges and prefixes in order of the keys
+engine_tests!(scan);
+
+fn write_batch<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    engine.set("from".to_owned(), "10".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("to".to_owned(), "10".to_owned())
+        .remove("from".to_owned())
+        .set("to".to_owned(), "20".to_owned());
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("from".to_owned())?, None);
+    assert_eq!(engine.get("to".to_owned())?, Some("20".to_owned()));
+
+    // a missing key fails the whole batch
+    let mut batch = WriteBatch::new();
+    batch
+        .set("other".to_owned(), "1".to_owned())
+        .remove("to".to_owned())
+        .remove("from".to_owned());
+    assert!(matches!(
+        engine.write_batch(batch),
+        Err(KvsError::KeyNotFoundError)
+    ));
+    assert_eq!(engine.get("other".to_owned())?, None);
+    assert_eq!(engine.get("to".to_owned())?, Some("20".to_owned()));
+
+    engine.write_batch(WriteBatch::new())?;
+    drop(engine);
+
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    assert_eq!(engine.discarded_bytes(), 0);
+    assert_eq!(engine.get("from".to_owned())?, None);
+    assert_eq!(engine.get("to".to_owned())?, Some("20".to_owned()));
+    Ok(())
+}
+
+// Should apply all operations of a batch or none of them
+engine_tests!(write_batch);
+
+// Should drop a batch whose commit marker never made it to the log
+#[test]
+fn recover_incomplete_batch() -> Result<()> {
+    for &format in &[LogFormat::Json, LogFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_format(temp_dir.path(), format)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        let log = temp_dir.path().join("1.log");
+        let len = std::fs::metadata(&log)?.len();
+        let mut batch = WriteBatch::new();
+        batch
+            .set("key2".to_owned(), "value2".to_owned())
+            .remove("key1".to_owned());
+        store.write_batch(batch)?;
+        drop(store);
+
+        // cut off the commit marker
+        let file = std::fs::OpenOptions::new().write(true).open(&log)?;
+        file.set_len(std::fs::metadata(&log)?.len() - 2)?;
+        drop(file);
+
+        let store = KvStore::open_with_format(temp_dir.path(), format)?;
+        assert!(store.discarded_bytes() > 0);
+        assert_eq!(std::fs::metadata(&log)?.len(), len);
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(store.get("key2".to_owned())?, None);
+    }
+    Ok(())
+}
+
+// Should refuse batch markers out of place and a batch left open in an older segment
+#[test]
+fn corrupted_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Json)?;
+    // a batch of one operation is written without markers
+    for key in &["key1", "key2"] {
+        let mut batch = WriteBatch::new();
+        batch
+            .set(key.to_string(), "value".to_owned())
+            .set(format!("{}-copy", key), "value".to_owned());
+        store.write_batch(batch)?;
+    }
+    drop(store);
+    let log = temp_dir.path().join("1.log");
+    let content = std::fs::read_to_string(&log)?;
+
+    // the first batch loses its commit marker, the second begins inside it
+    let commit = content.find("Commit").expect("a commit marker");
+    let start = content[..commit].rfind('\n').map_or(0, |at| at + 1);
+    let end = commit + content[commit..].find('\n').unwrap() + 1;
+    std::fs::write(&log, format!("{}{}", &content[..start], &content[end..]))?;
+    assert!(matches!(
+        KvStore::open_with_format(temp_dir.path(), LogFormat::Json),
+        Err(KvsError::CorruptedLogError)
+    ));
+
+    // the second batch loses its commit marker but a newer segment follows
+    let last = content.trim_end().rfind('\n').unwrap() + 1;
+    std::fs::write(&log, &content[..last])?;
+    std::fs::write(temp_dir.path().join("2.log"), "")?;
+    assert!(matches!(
+        KvStore::open_with_format(temp_dir.path(), LogFormat::Json),
+        Err(KvsError::CorruptedLogError)
+    ));
+
+    // the same log as the newest segment drops the open batch
+    std::fs::remove_file(temp_dir.path().join("2.log"))?;
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Json)?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+fn compare_and_swap<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    let cas = |expected: Option<&str>, new: Option<&str>| {
+        engine.compare_and_swap(
+            "key1".to_owned(),
+            expected.map(str::to_owned),
+            new.map(str::to_owned),
+        )
+    };
+    assert_eq!(cas(None, Some("1"))?, Ok(()));
+    assert_eq!(cas(None, Some("2"))?, Err(Some("1".to_owned())));
+    assert_eq!(cas(Some("2"), Some("3"))?, Err(Some("1".to_owned())));
+    assert_eq!(cas(Some("1"), Some("2"))?, Ok(()));
+    assert_eq!(engine.get("key1".to_owned())?, Some("2".to_owned()));
+
+    assert_eq!(cas(Some("2"), None)?, Ok(()));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(cas(Some("2"), None)?, Err(None));
+    assert_eq!(cas(None, None)?, Ok(()));
+    drop(engine);
+
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    engine
+        .compare_and_swap("key1".to_owned(), None, Some("x".to_owned()))?
+        .expect("the key was removed");
+    drop(engine);
+
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("x".to_owned()));
+    Ok(())
+}
+
+// Should only swap values that are the expected ones
+engine_tests!(compare_and_swap);
+
+// Concurrent increments through compare-and-swap should never be lost
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("counter".to_owned(), "0".to_owned())?;
+    let mut handles = Vec::new();
+    for _ in 0..8 {
+        let store = store.clone();
+        handles.push(thread::spawn(move || {
+            for _ in 0..50 {
+                let mut current = store.get("counter".to_owned()).unwrap();
+                loop {
+                    let next = current.as_deref().unwrap().parse::<u32>().unwrap() + 1;
+                    match store
+                        .compare_and_swap("counter".to_owned(), current, Some(next.to_string()))
+                        .unwrap()
+                    {
+                        Ok(()) => break,
+                        Err(found) => current = found,
+                    }
+                }
+            }
+        }));
+    }
+    for handle in handles {
+        handle.join().unwrap();
+    }
+    assert_eq!(store.get("counter".to_owned())?, Some("400".to_owned()));
+    Ok(())
+}
+
+fn snapshot<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    for key in &["a", "b", "c"] {
+        engine.set(key.to_string(), format!("old-{}", key))?;
+    }
+    let snapshot = engine.snapshot()?;
+    engine.set("a".to_owned(), "new-a".to_owned())?;
+    engine.remove("b".to_owned())?;
+    engine.set("d".to_owned(), "new-d".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("b".to_owned(), "new-b".to_owned())
+        .remove("c".to_owned());
+    engine.write_batch(batch)?;
+    let later = engine.snapshot()?;
+    engine.set("c".to_owned(), "newer-c".to_owned())?;
+
+    assert_eq!(snapshot.get("a".to_owned())?, Some("old-a".to_owned()));
+    assert_eq!(snapshot.get("d".to_owned())?, None);
+    let pairs = snapshot.scan(..)?.collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "old-a".to_owned()),
+            ("b".to_owned(), "old-b".to_owned()),
+            ("c".to_owned(), "old-c".to_owned()),
+        ]
+    );
+    let pairs = later
+        .scan_prefix("".to_owned())?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![
+            ("a".to_owned(), "new-a".to_owned()),
+            ("b".to_owned(), "new-b".to_owned()),
+            ("d".to_owned(), "new-d".to_owned()),
+        ]
+    );
+    drop(snapshot);
+    assert_eq!(later.get("c".to_owned())?, None);
+    assert_eq!(engine.get("c".to_owned())?, Some("newer-c".to_owned()));
+    Ok(())
+}
+
+// Should keep reading the values as of the creation of the snapshot
+engine_tests!(snapshot);
+
+fn snapshot_during_writes<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    let key = |i: usize| format!("key{:05}", i);
+    for i in 0..5000 {
+        engine.set(key(i), "0".to_owned())?;
+    }
+
+    // every round writes its number to the keys from the first one, so a snapshot
+    // sees the number of a round on the keys below some point and the one before above it
+    let writer = {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for round in 1..=5 {
+                for chunk in 0..50 {
+                    let mut batch = WriteBatch::new();
+                    for i in chunk * 100..(chunk + 1) * 100 {
+                        batch.set(key(i), round.to_string());
+                    }
+                    engine.write_batch(batch)?;
+                }
+            }
+            Ok(())
+        })
+    };
+    while !writer.is_finished() {
+        let rounds = engine
+            .snapshot()?
+            .scan(..)?
+            .map(|pair| pair.map(|(_, value)| value.parse::<u32>().unwrap()))
+            .collect::<Result<Vec<_>>>()?;
+        assert_eq!(rounds.len(), 5000);
+        let newest = rounds
+            .iter()
+            .take_while(|round| **round == rounds[0])
+            .count();
+        assert!(rounds[newest..].iter().all(|round| round + 1 == rounds[0]));
+    }
+    writer.join().unwrap()
+}
+
+// A snapshot taken while the keys are written should see them as of one moment
+engine_tests!(snapshot_during_writes);
+
+// Compaction should keep the versions a live snapshot reads, and only those
+#[test]
+fn snapshot_during_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .manual_compaction(true)
+        .open(temp_dir.path())?;
+    let log_size = || -> u64 {
+        std::fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension() == Some("log".as_ref()))
+            .map(|path| std::fs::metadata(path).unwrap().len())
+            .sum()
+    };
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".repeat(100))?;
+    }
+    let snapshot = store.snapshot()?;
+    for iter in 1..10 {
+        for key_id in 0..100 {
+            store.set(format!("key{}", key_id), iter.to_string().repeat(100))?;
+        }
+    }
+    store.remove("key0".to_owned())?;
+
+    store.compact()?;
+    let with_snapshot = log_size();
+    assert_eq!(snapshot.get("key0".to_owned())?, Some("0".repeat(100)));
+    assert_eq!(snapshot.scan(..)?.count(), 100);
+    assert_eq!(store.get("key0".to_owned())?, None);
+    assert_eq!(store.get("key1".to_owned())?, Some("9".repeat(100)));
+
+    drop(snapshot);
+    store.compact()?;
+    assert!(log_size() < with_snapshot);
+    assert_eq!(store.get("key1".to_owned())?, Some("9".repeat(100)));
+    assert_eq!(store.scan(..)?.count(), 99);
+    Ok(())
+}
+
+// The versions kept for a snapshot should not come back when the compacted segment
+// is replayed without its hint file
+#[test]
+fn reopen_compacted_snapshot_without_hint() -> Result<()> {
+    for &format in &[LogFormat::Json, LogFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .format(format)
+            .manual_compaction(true)
+            .open(temp_dir.path())?;
+        store.set("k".to_owned(), "v1".to_owned())?;
+        store.set("gone".to_owned(), "x".to_owned())?;
+        let snapshot = store.snapshot()?;
+        store.set("k".to_owned(), "v2".to_owned())?;
+        store.remove("gone".to_owned())?;
+        store.compact()?;
+        assert_eq!(snapshot.get("k".to_owned())?, Some("v1".to_owned()));
+        assert_eq!(snapshot.get("gone".to_owned())?, Some("x".to_owned()));
+        drop(snapshot);
+        drop(store);
+
+        std::fs::remove_file(temp_dir.path().join("2.hint"))?;
+        let store = KvStoreOptions::new().format(format).open(temp_dir.path())?;
+        assert_eq!(store.get("k".to_owned())?, Some("v2".to_owned()));
+        assert_eq!(store.get("gone".to_owned())?, None);
+        assert_eq!(store.keys()?, vec!["k".to_owned()]);
+    }
+    Ok(())
+}
+
+// A snapshot should not see any part of a batch written while it is read
+#[test]
+fn concurrent_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("a".to_owned(), "0".to_owned())?;
+    store.set("b".to_owned(), "0".to_owned())?;
+    let writer = {
+        let store = store.clone();
+        thread::spawn(move || {
+            for iter in 1..200 {
+                let mut batch = WriteBatch::new();
+                batch
+                    .set("a".to_owned(), iter.to_string())
+                    .set("b".to_owned(), iter.to_string());
+                store.write_batch(batch).unwrap();
+            }
+        })
+    };
+    for _ in 0..200 {
+        let snapshot = store.snapshot()?;
+        let values: Vec<String> = snapshot
+            .scan(..)?
+            .map(|pair| pair.map(|(_, value)| value))
+            .collect::<Result<_>>()?;
+        assert_eq!(values.len(), 2);
+        assert_eq!(values[0], values[1]);
+    }
+    writer.join().unwrap();
+    Ok(())
+}
+
+fn ttl<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    engine.set("key1".to_owned(), "old".to_owned())?;
+    engine.set_with_ttl(
+        "key1".to_owned(),
+        "value1".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    engine.set_with_ttl(
+        "key2".to_owned(),
+        "value2".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    engine.set_with_ttl(
+        "key3".to_owned(),
+        "value3".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    engine.set_with_ttl(
+        "key4".to_owned(),
+        "value4".to_owned(),
+        Duration::from_millis(300),
+    )?;
+    engine.set("key4".to_owned(), "value4".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.keys()?.len(), 4);
+
+    thread::sleep(Duration::from_millis(400));
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("key4".to_owned())?, Some("value4".to_owned()));
+    assert_eq!(engine.keys()?, vec!["key3".to_owned(), "key4".to_owned()]);
+    assert_eq!(engine.scan(..)?.count(), 2);
+    assert_eq!(
+        engine.scan_prefix_keys("key")?,
+        vec![b"key3".to_vec(), b"key4".to_vec()]
+    );
+    assert!(matches!(
+        engine.remove("key2".to_owned()),
+        Err(KvsError::KeyNotFoundError)
+    ));
+    assert_eq!(
+        engine.compare_and_swap("key2".to_owned(), None, Some("new".to_owned()))?,
+        Ok(())
+    );
+    assert_eq!(engine.get("key2".to_owned())?, Some("new".to_owned()));
+    drop(engine);
+
+    // the expiry is read back from the log
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Should hide the keys whose time to live has passed
+engine_tests!(ttl);
+
+// Compaction should leave the expired logs behind and keep the expiry of the others
+#[test]
+fn ttl_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStoreOptions::new()
+        .manual_compaction(true)
+        .open(temp_dir.path())?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+        store.set_with_ttl(
+            format!("key{}", key_id),
+            "short".to_owned(),
+            Duration::from_millis(200),
+        )?;
+    }
+    store.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(3600),
+    )?;
+    thread::sleep(Duration::from_millis(300));
+    store.compact()?;
+    drop(store);
+
+    let hints: Vec<_> = std::fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension() == Some("hint".as_ref()))
+        .collect();
+    assert_eq!(hints.len(), 1);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.keys()?, vec!["long".to_owned()]);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert_eq!(store.get("long".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+fn binary<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    let (key, value) = (vec![0, 159, 146, 150], vec![255, b'\n', 0, b'\t']);
+    engine.set(key.clone(), value.clone())?;
+    engine.set(vec![0, 1], vec![])?;
+    engine.set("text".to_owned(), "value".to_owned())?;
+    assert_eq!(engine.get_bytes(key.clone())?, Some(value.clone()));
+    assert_eq!(engine.get_bytes(vec![0, 1])?, Some(vec![]));
+
+    // the `String` api reports the bytes that are not utf-8 instead of panicking
+    match engine.get(key.clone()) {
+        Err(KvsError::Utf8Error(_)) => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(engine.keys().is_err());
+    assert_eq!(engine.get("text".to_owned())?, Some("value".to_owned()));
+
+    let pairs = engine
+        .scan_prefix_bytes(vec![0])?
+        .collect::<Result<Vec<_>>>()?;
+    assert_eq!(
+        pairs,
+        vec![(vec![0, 1], vec![]), (key.clone(), value.clone())]
+    );
+    assert_eq!(
+        engine.compare_and_swap_bytes(key.clone(), None, Some(vec![1]))?,
+        Err(Some(value.clone()))
+    );
+    engine
+        .compare_and_swap_bytes(key.clone(), Some(value), Some(vec![1]))?
+        .expect("the value was the expected one");
+    engine.remove(vec![0, 1])?;
+    assert_eq!(engine.keys_bytes()?, vec![key, b"text".to_vec()]);
+    drop(engine);
+
+    // the bytes are read back from the log, and from the hint file after a compaction
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    assert_eq!(engine.get_bytes(vec![0, 159, 146, 150])?, Some(vec![1]));
+    engine.compact()?;
+    drop(engine);
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    assert_eq!(engine.get_bytes(vec![0, 159, 146, 150])?, Some(vec![1]));
+    assert_eq!(engine.get_bytes(vec![0, 1])?, None);
+    Ok(())
+}
+
+// Keys and values should be stored as arbitrary bytes
+engine_tests!(binary);
+
+fn stream<E: TestEngine>(format: LogFormat) -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    let value: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    engine.set_reader("large".to_owned(), &value[..])?;
+    let mut read = Vec::new();
+    engine
+        .get_reader("large".to_owned())?
+        .expect("the value was set")
+        .read_to_end(&mut read)?;
+    assert!(read == value);
+    assert_eq!(engine.get_bytes("large".to_owned())?, Some(value.clone()));
+    assert!(engine.get_reader("missing".to_owned())?.is_none());
+
+    // a value being read survives the compaction removing its segment
+    let mut reader = engine
+        .get_reader("large".to_owned())?
+        .expect("the value was set");
+    engine.compact()?;
+    let mut read = Vec::new();
+    reader.read_to_end(&mut read)?;
+    assert!(read == value);
+    drop(reader);
+    drop(engine);
+
+    let engine = E::open_with_format(temp_dir.path(), format)?;
+    assert_eq!(engine.get_bytes("large".to_owned())?, Some(value));
+    Ok(())
+}
+
+// Values should be written from and read into readers
+engine_tests!(stream);
+
+// A streamed value not matching its checksum should fail the read at its end
+#[test]
+fn stream_corrupted_value() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_format(temp_dir.path(), LogFormat::Binary)?;
+    store.set_reader("key1".to_owned(), &[7; 100_000][..])?;
+
+    // damaged behind the back of the open store, which would cut it off when reopened
+    let path = temp_dir.path().join("1.log");
+    let mut log = std::fs::read(&path)?;
+    let last = log.len() - 1;
+    log[last] ^= 0xff;
+    std::fs::write(&path, log)?;
+
+    let mut reader = store
+        .get_reader("key1".to_owned())?
+        .expect("the value was set");
+    let mut read = Vec::new();
+    assert!(reader.read_to_end(&mut read).is_err());
+    Ok(())
+}
+
+fn verbose_value(i: usize) -> String {
+    format!(
+        "{{\"id\": {}, \"items\": [{}]}}",
+        i,
+        (0..50)
+            .map(|n| format!("{{\"name\": \"item\", \"count\": {}}}", n))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+// Values should be compressed above the threshold and logs mixing both should replay
+#[test]
+fn compressed_values() -> Result<()> {
+    for &format in &[LogFormat::Json, LogFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStoreOptions::new()
+            .format(format)
+            .compression(Compression::Deflate)
+            .compression_threshold(64)
+            .open(temp_dir.path())?;
+        assert_eq!(store.stats().compression_ratio(), 1.0);
+        store.set("small".to_owned(), "value".to_owned())?;
+        let stats = store.stats();
+        assert_eq!(stats.value_bytes, stats.stored_value_bytes);
+
+        for i in 0..10 {
+            store.set(format!("key{}", i), verbose_value(i))?;
+        }
+        store.set_with_ttl("ttl".to_owned(), verbose_value(10), Duration::from_secs(60))?;
+        let mut batch = WriteBatch::new();
+        batch.set("batch".to_owned(), verbose_value(11));
+        store.write_batch(batch)?;
+        let stats = store.stats();
+        assert!(stats.stored_value_bytes < stats.value_bytes);
+        assert!(stats.compression_ratio() > 2.0);
+
+        let mut read = String::new();
+        store
+            .get_reader("key0".to_owned())?
+            .expect("the value was set")
+            .read_to_string(&mut read)?;
+        assert_eq!(read, verbose_value(0));
+        drop(store);
+
+        // reopened without compression, the values already stored still count
+        // and the new values are stored raw
+        let store = KvStore::open_with_format(temp_dir.path(), format)?;
+        assert_eq!(store.stats().value_bytes, stats.value_bytes);
+        assert_eq!(store.stats().stored_value_bytes, stats.stored_value_bytes);
+        let raw_len = verbose_value(12).len() as u64;
+        store.set("raw".to_owned(), verbose_value(12))?;
+        let reopened = store.stats();
+        assert_eq!(reopened.value_bytes, stats.value_bytes + raw_len);
+        assert_eq!(
+            reopened.stored_value_bytes,
+            stats.stored_value_bytes + raw_len
+        );
+        // an overwritten value no longer counts
+        store.set("key0".to_owned(), "short".to_owned())?;
+        assert!(store.stats().value_bytes < reopened.value_bytes);
+        store.set("key0".to_owned(), verbose_value(0))?;
+        let stats = store.stats();
+        for i in 0..10 {
+            assert_eq!(store.get(format!("key{}", i))?, Some(verbose_value(i)));
+        }
+        drop(store);
+
+        // re-encoded in the other format by compaction
+        let other = match format {
+            LogFormat::Json => LogFormat::Binary,
+            LogFormat::Binary => LogFormat::Json,
+        };
+        let store = KvStore::open_with_format(temp_dir.path(), other)?;
+        store.compact()?;
+        drop(store);
+        let store = KvStore::open_with_format(temp_dir.path(), other)?;
+        assert_eq!(store.stats().value_bytes, stats.value_bytes);
+        assert_eq!(store.stats().stored_value_bytes, stats.stored_value_bytes);
+        assert_eq!(store.get("small".to_owned())?, Some("value".to_owned()));
+        assert_eq!(store.get("key9".to_owned())?, Some(verbose_value(9)));
+        assert_eq!(store.get("ttl".to_owned())?, Some(verbose_value(10)));
+        assert_eq!(store.get("batch".to_owned())?, Some(verbose_value(11)));
+        assert_eq!(store.get("raw".to_owned())?, Some(verbose_value(12)));
+    }
+    Ok(())
+}
+
+fn encrypted(format: LogFormat, key: u8) -> KvStoreOptions {
+    let mut options = KvStoreOptions::new();
+    options
+        .format(format)
+        .manual_compaction(true)
+        .encryption_key(EncryptionKey::new([key; 32]));
+    options
+}
+
+// whether any file of the store holds the given bytes in the clear
+fn leaks(dir: &Path, secret: &[u8]) -> bool {
+    std::fs::read_dir(dir)
+        .expect("unable to read the store directory")
+        .map(|entry| std::fs::read(entry.unwrap().path()).unwrap())
+        .any(|content| content.windows(secret.len()).any(|window| window == secret))
+}
+
+// Records and hint files should be written encrypted and read back with the key
+#[test]
+fn encrypted_store() -> Result<()> {
+    for &format in &[LogFormat::Json, LogFormat::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = encrypted(format, 1).open(temp_dir.path())?;
+        store.set("secret-key1".to_owned(), "secret-value1".to_owned())?;
+        store.set("secret-key2".to_owned(), "secret-value2".to_owned())?;
+        store.compact()?;
+        let mut batch = WriteBatch::new();
+        batch.set("secret-key3".to_owned(), "secret-value3".to_owned());
+        batch.remove("secret-key2".to_owned());
+        store.write_batch(batch)?;
+        store.set_reader("secret-key4".to_owned(), &b"secret-value4"[..])?;
+        drop(store);
+        assert!(!leaks(temp_dir.path(), b"secret"));
+
+        let store = encrypted(format, 1).open(temp_dir.path())?;
+        assert_eq!(
+            store.get("secret-key1".to_owned())?,
+            Some("secret-value1".to_owned())
+        );
+        assert_eq!(store.get("secret-key2".to_owned())?, None);
+        assert_eq!(
+            store.get("secret-key3".to_owned())?,
+            Some("secret-value3".to_owned())
+        );
+        let mut read = String::new();
+        store
+            .get_reader("secret-key4".to_owned())?
+            .expect("the value was set")
+            .read_to_string(&mut read)?;
+        assert_eq!(read, "secret-value4");
+    }
+    Ok(())
+}
+
+// Should refuse to open encrypted files without their key
+#[test]
+fn wrong_encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = encrypted(LogFormat::Binary, 1).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    for options in &[encrypted(LogFormat::Binary, 2), KvStoreOptions::new()] {
+        match options.open(temp_dir.path()) {
+            Err(KvsError::WrongKeyError) => (),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("opened with the wrong key"),
+        }
+    }
+    Ok(())
+}
+
+// A changed record should fail authentication instead of being read or cut off
+#[test]
+fn tampered_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = encrypted(LogFormat::Binary, 1).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+
+    // the first record starts right after the 25 bytes of the preamble
+    let path = temp_dir.path().join("1.log");
+    let mut log = std::fs::read(&path)?;
+    log[30] ^= 1;
+    std::fs::write(&path, &log)?;
+    match store.get("key1".to_owned()) {
+        Err(KvsError::TamperedDataError) => (),
+        other => panic!("tampered record accepted: {:?}", other),
+    }
+    drop(store);
+
+    match encrypted(LogFormat::Binary, 1).open(temp_dir.path()) {
+        Err(KvsError::TamperedDataError) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a tampered log"),
+    }
+
+    // a complete last record failing authentication was changed too, not torn
+    log[30] ^= 1;
+    let last = log.len() - 1;
+    log[last] ^= 1;
+    std::fs::write(&path, &log)?;
+    match encrypted(LogFormat::Binary, 1).open(temp_dir.path()) {
+        Err(KvsError::TamperedDataError) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a tampered log"),
+    }
+
+    // a last record cut short could be one whose length was changed
+    log[last] ^= 1;
+    std::fs::write(&path, &log[..last])?;
+    match encrypted(LogFormat::Binary, 1).open(temp_dir.path()) {
+        Err(KvsError::TamperedDataError) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a tampered log"),
+    }
+
+    // a length making the first record run past the end of the file must not
+    // get the records after it cut off as a torn tail
+    let length = log[25 + 3];
+    log[25 + 3] = 0x40;
+    std::fs::write(&path, &log)?;
+    match encrypted(LogFormat::Binary, 1).open(temp_dir.path()) {
+        Err(KvsError::TamperedDataError) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a tampered log"),
+    }
+    assert_eq!(std::fs::read(&path)?, log);
+
+    // fewer bytes than any sealed record are what a crash leaves behind, they are cut off
+    log[25 + 3] = length;
+    let second = 25 + 4 + u32::from_le_bytes([log[25], log[26], log[27], log[28]]) as usize + 16;
+    std::fs::write(&path, &log[..second + 10])?;
+    let store = encrypted(LogFormat::Binary, 1).open(temp_dir.path())?;
+    assert!(store.discarded_bytes() > 0);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+// the files of the store with the given extension
+fn files_with(dir: &Path, extension: &str) -> Vec<std::path::PathBuf> {
+    std::fs::read_dir(dir)
+        .expect("unable to read the store directory")
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == extension))
+        .collect()
+}
+
+// An encrypted store should refuse files in plain text and a format byte changed
+#[test]
+fn plaintext_in_encrypted_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = encrypted(LogFormat::Binary, 1).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    drop(store);
+    let hint = files_with(temp_dir.path(), "hint")
+        .pop()
+        .expect("a hint file");
+    let sealed_hint = std::fs::read(&hint)?;
+
+    let plain_dir = TempDir::new().expect("unable to create temporary working directory");
+    let plain = KvStoreOptions::new()
+        .format(LogFormat::Binary)
+        .manual_compaction(true)
+        .open(plain_dir.path())?;
+    plain.set("key1".to_owned(), "forged".to_owned())?;
+    plain.compact()?;
+    drop(plain);
+    let plain_hint = files_with(plain_dir.path(), "hint")
+        .pop()
+        .expect("a hint file");
+    let plain_log = plain_hint.with_extension("log");
+
+    let refused = |what: &str| match encrypted(LogFormat::Binary, 1).open(temp_dir.path()) {
+        Err(KvsError::TamperedDataError) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened a store with {}", what),
+    };
+    std::fs::copy(&plain_hint, &hint)?;
+    refused("a plain hint file");
+    std::fs::write(&hint, &sealed_hint)?;
+    std::fs::copy(&plain_log, temp_dir.path().join("100.log"))?;
+    refused("a plain segment");
+    std::fs::remove_file(temp_dir.path().join("100.log"))?;
+
+    // the compacted segment read as json instead of binary
+    std::fs::remove_file(&hint)?;
+    let log = hint.with_extension("log");
+    let mut content = std::fs::read(&log)?;
+    content[8] ^= 1;
+    std::fs::write(&log, &content)?;
+    refused("a changed format");
+    content[8] ^= 1;
+    std::fs::write(&log, &content)?;
+
+    let store = encrypted(LogFormat::Binary, 1).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    Ok(())
+}
+
+// Compaction should re-encrypt the store with the current key
+#[test]
+fn rotate_encryption_key() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = encrypted(LogFormat::Json, 1).open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.compact()?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    let store = encrypted(LogFormat::Json, 2)
+        .decryption_key(EncryptionKey::new([1; 32]))
+        .open(temp_dir.path())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    store.compact()?;
+    drop(store);
+
+    let store = encrypted(LogFormat::Json, 2).open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// Opening a plain store with a key should encrypt it on the background thread
+// once the migration is asked for
+#[test]
+fn encrypt_plain_store() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("secret-key".to_owned(), "secret-value".to_owned())?;
+    drop(store);
+
+    match KvStoreOptions::new()
+        .encryption_key(EncryptionKey::new([1; 32]))
+        .open(temp_dir.path())
+    {
+        Err(KvsError::TamperedDataError) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("read a plain segment with a key"),
+    }
+    let store = KvStoreOptions::new()
+        .encryption_key(EncryptionKey::new([1; 32]))
+        .migrate_plaintext(true)
+        .open(temp_dir.path())?;
+    assert!(wait_for_compaction(temp_dir.path(), 5000));
+    drop(store);
+    assert!(!leaks(temp_dir.path(), b"secret"));
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::WrongKeyError) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("opened without the key"),
+    }
+    let store = encrypted(LogFormat::Json, 1).open(temp_dir.path())?;
+    assert_eq!(
+        store.get("secret-key".to_owned())?,
+        Some("secret-value".to_owned())
+    );
+    Ok(())
+}
@@ -0,0 +1,164 @@
+use kvs::protocol::{read_frame, write_frame, MAX_FRAME_LEN};
+use kvs::{Command, KvsError, Request, Response, Result, WriteBatch};
+use std::io::Cursor;
+use std::time::Duration;
+
+// Frames written one after another should be read back in order
+#[test]
+fn frame_round_trip() -> Result<()> {
+    let mut buf = Vec::new();
+    let set = Command::Set {
+        key: "key1".to_owned(),
+        value: "value1".to_owned(),
+        ttl: Some(Duration::from_secs(30)),
+    };
+    write_frame(&mut buf, &set)?;
+    write_frame(&mut buf, &Response::Value(b"Key not found".to_vec()))?;
+    write_frame(&mut buf, &Response::NotFound)?;
+
+    let mut reader = Cursor::new(buf);
+    match read_frame(&mut reader)? {
+        Some(Command::Set { key, value, ttl }) => {
+            assert_eq!(key, "key1");
+            assert_eq!(value, "value1");
+            assert_eq!(ttl, Some(Duration::from_secs(30)));
+        }
+        other => panic!("unexpected command: {:?}", other),
+    }
+    assert_eq!(
+        read_frame::<Response>(&mut reader)?,
+        Some(Response::Value(b"Key not found".to_vec()))
+    );
+    assert_eq!(
+        read_frame::<Response>(&mut reader)?,
+        Some(Response::NotFound)
+    );
+    assert_eq!(read_frame::<Response>(&mut reader)?, None);
+    Ok(())
+}
+
+// Values far larger than a single read should not be truncated
+#[test]
+fn frame_large_value() -> Result<()> {
+    let value = vec![b'v'; 1024 * 1024];
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &Response::Value(value.clone()))?;
+
+    let mut reader = Cursor::new(buf);
+    assert_eq!(
+        read_frame::<Response>(&mut reader)?,
+        Some(Response::Value(value))
+    );
+    Ok(())
+}
+
+// A frame cut in the middle or announcing a huge body is an error
+#[test]
+fn frame_malformed() -> Result<()> {
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &Response::Ok)?;
+    buf.pop();
+    assert!(read_frame::<Response>(&mut Cursor::new(buf)).is_err());
+
+    // the body announced is not taken for granted before it arrives
+    let mut buf = MAX_FRAME_LEN.to_be_bytes().to_vec();
+    buf.extend_from_slice(b"\"Ok\"");
+    match read_frame::<Response>(&mut Cursor::new(buf)) {
+        Err(KvsError::IoError(ref e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => (),
+        other => panic!("unexpected result: {:?}", other),
+    }
+
+    let buf = (MAX_FRAME_LEN + 1).to_be_bytes().to_vec();
+    match read_frame::<Response>(&mut Cursor::new(buf)) {
+        Err(KvsError::StringError(_)) => Ok(()),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+// Requests of clients that do not know about ttls should still be understood
+#[test]
+fn set_without_ttl() -> Result<()> {
+    let set: Command = serde_json::from_str(r#"{"Set":{"key":"key1","value":"value1"}}"#)?;
+    match set {
+        Command::Set { ttl, .. } => assert_eq!(ttl, None),
+        other => panic!("unexpected command: {:?}", other),
+    }
+    Ok(())
+}
+
+// Keys and values that are not utf-8 should go through the frames unchanged
+#[test]
+fn binary_frames() -> Result<()> {
+    let (key, value) = (vec![0, 159, 146, 150], vec![255, 0, 10, 13]);
+    let mut batch = WriteBatch::new();
+    batch.set(key.clone(), value.clone()).remove(vec![192]);
+    let requests = vec![
+        Request::Set {
+            key: key.clone(),
+            value: value.clone(),
+            ttl: None,
+        },
+        Request::Cas {
+            key: key.clone(),
+            expected: Some(value.clone()),
+            new: None,
+        },
+        Request::Batch {
+            ops: batch.into_iter().collect(),
+        },
+    ];
+    let responses = vec![
+        Response::Value(value.clone()),
+        Response::Pairs(vec![(key.clone(), value.clone()), (b"k".to_vec(), vec![])]),
+        Response::Conflict(Some(value)),
+    ];
+
+    let mut buf = Vec::new();
+    for request in &requests {
+        write_frame(&mut buf, request)?;
+    }
+    for response in &responses {
+        write_frame(&mut buf, response)?;
+    }
+    let mut reader = Cursor::new(buf);
+    for request in requests {
+        assert_eq!(read_frame::<Request>(&mut reader)?, Some(request));
+    }
+    for response in responses {
+        assert_eq!(read_frame::<Response>(&mut reader)?, Some(response));
+    }
+
+    // they are base64 in the json, the arrays of numbers of older versions are still read
+    let mut frame = Vec::new();
+    write_frame(&mut frame, &Response::Value(vec![0, 159, 146, 150]))?;
+    assert_eq!(&frame[4..], &br#"{"Value":{"base64":"AJ+Slg=="}}"#[..]);
+    let legacy = br#"{"Value":[0,159,146,150]}"#;
+    let mut frame = (legacy.len() as u32).to_be_bytes().to_vec();
+    frame.extend_from_slice(legacy);
+    assert_eq!(
+        read_frame::<Response>(&mut Cursor::new(frame))?,
+        Some(Response::Value(vec![0, 159, 146, 150]))
+    );
+    Ok(())
+}
+
+// The frames of a `Command` should be read as the same `Request`
+#[test]
+fn command_as_request() -> Result<()> {
+    let cas = Command::Cas {
+        key: "key1".to_owned(),
+        expected: None,
+        new: Some("value1".to_owned()),
+    };
+    let mut buf = Vec::new();
+    write_frame(&mut buf, &cas)?;
+    assert_eq!(
+        read_frame::<Request>(&mut Cursor::new(buf))?,
+        Some(Request::Cas {
+            key: b"key1".to_vec(),
+            expected: None,
+            new: Some(b"value1".to_vec()),
+        })
+    );
+    Ok(())
+}
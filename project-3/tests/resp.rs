@@ -0,0 +1,169 @@
+#![allow(clippy::zombie_processes)]
+
+use assert_cmd::prelude::*;
+use kvs::resp::{execute, glob_match, read_command, Reply, Value};
+use kvs::{KvStore, Result};
+use std::io::{BufRead, BufReader, Cursor, Write};
+use std::net::TcpStream;
+use std::process::Command;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+// reads one complete reply, nested arrays included
+fn read_reply(reader: &mut impl BufRead) -> String {
+    let mut line = String::new();
+    reader.read_line(&mut line).unwrap();
+    let mut reply = line.clone();
+    let len: i64 = line[1..].trim_end().parse().unwrap_or(0);
+    match line.as_bytes()[0] {
+        b'$' if len >= 0 => {
+            let mut bulk = vec![0; len as usize + 2];
+            reader.read_exact(&mut bulk).unwrap();
+            reply.push_str(&String::from_utf8(bulk).unwrap());
+        }
+        b'*' => {
+            for _ in 0..len {
+                reply.push_str(&read_reply(reader));
+            }
+        }
+        _ => (),
+    }
+    reply
+}
+
+fn resp_access_server(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr, "--protocol", "resp"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let stream = TcpStream::connect(addr).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut writer = stream;
+    let mut request = |command: &str| {
+        writer.write_all(command.as_bytes()).unwrap();
+        read_reply(&mut reader)
+    };
+
+    assert_eq!(request("*1\r\n$4\r\nPING\r\n"), "+PONG\r\n");
+    assert_eq!(request("PING hello\r\n"), "$5\r\nhello\r\n");
+    assert_eq!(request("*2\r\n$3\r\nGET\r\n$4\r\nkey1\r\n"), "$-1\r\n");
+    assert_eq!(
+        request("*3\r\n$3\r\nSET\r\n$4\r\nkey1\r\n$6\r\nvalue1\r\n"),
+        "+OK\r\n"
+    );
+    assert_eq!(request("SET key2 value2\r\n"), "+OK\r\n");
+    assert_eq!(request("SET other value3\r\n"), "+OK\r\n");
+    assert_eq!(
+        request("*2\r\n$3\r\nget\r\n$4\r\nkey1\r\n"),
+        "$6\r\nvalue1\r\n"
+    );
+    assert_eq!(request("EXISTS key1 key2 key3 key1\r\n"), ":3\r\n");
+    assert_eq!(request("DBSIZE\r\n"), ":3\r\n");
+    assert_eq!(
+        request("KEYS key*\r\n"),
+        "*2\r\n$4\r\nkey1\r\n$4\r\nkey2\r\n"
+    );
+    assert_eq!(request("DEL key1 key3\r\n"), ":1\r\n");
+    assert_eq!(request("KEYS *\r\n"), "*2\r\n$4\r\nkey2\r\n$5\r\nother\r\n");
+    assert!(request("INFO\r\n").contains("db0:keys=2"));
+    assert!(request("GET\r\n").starts_with("-ERR wrong number of arguments"));
+    assert!(request("FLUSHALL\r\n").starts_with("-ERR unknown command"));
+
+    assert_eq!(request("SET short value PX 200\r\n"), "+OK\r\n");
+    assert_eq!(request("SET long value ex 3600\r\n"), "+OK\r\n");
+    assert_eq!(request("GET short\r\n"), "$5\r\nvalue\r\n");
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(request("GET short\r\n"), "$-1\r\n");
+    assert_eq!(request("GET long\r\n"), "$5\r\nvalue\r\n");
+    assert!(request("SET key value EX 0\r\n").starts_with("-ERR invalid expire time"));
+    assert!(request("SET key value NX PX\r\n").starts_with("-ERR syntax error"));
+    assert_eq!(request("QUIT\r\n"), "+OK\r\n");
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait for the server to exit");
+}
+
+#[test]
+fn resp_access_server_kvs_engine() {
+    resp_access_server("kvs", "127.0.0.1:4010");
+}
+
+#[test]
+fn resp_access_server_sled_engine() {
+    resp_access_server("sled", "127.0.0.1:4011");
+}
+
+// Patterns of KEYS should behave like the ones of Redis
+#[test]
+fn resp_glob_match() {
+    assert!(glob_match(b"*", b""));
+    assert!(glob_match(b"*", b"key"));
+    assert!(glob_match(b"k?y", b"key"));
+    assert!(!glob_match(b"k?y", b"ky"));
+    assert!(glob_match(b"h[ae]llo", b"hallo"));
+    assert!(!glob_match(b"h[ae]llo", b"hillo"));
+    assert!(glob_match(b"h[^e]llo", b"hallo"));
+    assert!(!glob_match(b"h[^e]llo", b"hello"));
+    assert!(glob_match(b"h[a-c]llo", b"hbllo"));
+    assert!(glob_match(b"a\\*b", b"a*b"));
+    assert!(!glob_match(b"a\\*b", b"axb"));
+    assert!(glob_match(b"*:*:id", b"user:42:id"));
+    assert!(glob_match(b"*a", b"aba"));
+    assert!(!glob_match(b"*a", b"ab"));
+    assert!(glob_match(b"a*b*", b"axxbyy"));
+    assert!(glob_match(b"[ab", b"[ab"));
+    assert!(glob_match(b"**", b""));
+}
+
+// Many stars should not make a failing match take exponential time
+#[test]
+fn resp_glob_match_many_stars() {
+    let text = vec![b'a'; 200];
+    assert!(!glob_match(
+        &b"a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*b"[..],
+        &text
+    ));
+    assert!(glob_match(
+        &b"a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*a*"[..],
+        &text
+    ));
+}
+
+// Oversized lines and argument counts should be refused before anything is allocated
+#[test]
+fn resp_request_limits() {
+    let mut line = vec![b'A'; 128 * 1024];
+    line.extend_from_slice(b"\r\n");
+    assert!(read_command(&mut Cursor::new(line)).is_err());
+
+    let many = b"*67108864\r\n$4\r\nPING\r\n".to_vec();
+    assert!(read_command(&mut Cursor::new(many)).is_err());
+
+    let ping = b"*1\r\n$4\r\nPING\r\n".to_vec();
+    assert_eq!(
+        read_command(&mut Cursor::new(ping)).unwrap(),
+        Some(vec![b"PING".to_vec()])
+    );
+}
+
+// A command without any argument should get an error instead of bringing the server down
+#[test]
+fn resp_empty_command() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(
+        execute(&store, Vec::new()),
+        Reply::Value(Value::Error("ERR empty command".to_owned()))
+    );
+    assert_eq!(
+        execute(&store, vec![b"PING".to_vec()]),
+        Reply::Value(Value::Simple("PONG".to_owned()))
+    );
+    Ok(())
+}